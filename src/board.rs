@@ -0,0 +1,27 @@
+use crate::protocol::ChecksumAlgorithm;
+
+/// Defaults for a board we flash regularly, so it only takes `--board <name>`.
+#[derive(Debug)]
+pub struct BoardProfile {
+    pub name: &'static str,
+    /// Absolute address of the application slot, subtracted from UF2 target addresses.
+    pub offset: u32,
+    pub baud_rate: u32,
+    pub image_checksum: ChecksumAlgorithm,
+}
+
+pub const BOARDS: &[BoardProfile] = &[
+    // Application after a 16 KiB bootloader at the start of XIP flash.
+    BoardProfile { name: "rp2040", offset: 0x10004000, baud_rate: 9600, image_checksum: ChecksumAlgorithm::Sha256 },
+    // Application after the 32 KiB in sectors 0 and 1. The CRC unit makes CRC32 nearly free.
+    BoardProfile { name: "stm32f4", offset: 0x08008000, baud_rate: 115200, image_checksum: ChecksumAlgorithm::Crc32 },
+    // Application after the first 128 KiB sector.
+    BoardProfile { name: "stm32h7", offset: 0x08020000, baud_rate: 115200, image_checksum: ChecksumAlgorithm::Crc32 },
+];
+
+pub fn find_board(name: &str) -> Result<&'static BoardProfile, String> {
+    BOARDS.iter().find(|b| b.name.eq_ignore_ascii_case(name)).ok_or_else(|| {
+        let names: Vec<&str> = BOARDS.iter().map(|b| b.name).collect();
+        format!("Unknown board `{}`, known boards are: {}", name, names.join(", "))
+    })
+}
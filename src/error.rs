@@ -0,0 +1,398 @@
+use std::fmt;
+use crate::image::{ChecksumRegion, UF2_BLOCK_SIZE};
+use crate::protocol::{ChecksumAlgorithm, PageAddress, SerialFlasherResponse, PAGE_SIZE};
+
+/// Why a transport couldn't be opened, sorted into the cases a caller can do something about.
+#[derive(Debug)]
+pub enum TransportOpenError {
+    NoSuchPort(String),
+    PermissionDenied(String),
+    PortBusy(String),
+    BadAddress(String),
+    Other {
+        port: String,
+        error: serialport::Error,
+    },
+    /// Nothing accepted a TCP connection at the address.
+    Unreachable {
+        address: String,
+        error: std::io::Error,
+    },
+    /// The TLS session couldn't be set up, usually because the certificate wasn't trusted.
+    Tls {
+        address: String,
+        error: String,
+    },
+}
+
+impl TransportOpenError {
+    pub(crate) fn from_serialport(port: &str, error: serialport::Error) -> Self {
+        let port = port.to_string();
+
+        match error.kind() {
+            serialport::ErrorKind::NoDevice => TransportOpenError::NoSuchPort(port),
+            serialport::ErrorKind::InvalidInput => TransportOpenError::BadAddress(port),
+            serialport::ErrorKind::Io(std::io::ErrorKind::NotFound) => TransportOpenError::NoSuchPort(port),
+            serialport::ErrorKind::Io(std::io::ErrorKind::PermissionDenied) => TransportOpenError::PermissionDenied(port),
+            serialport::ErrorKind::Io(std::io::ErrorKind::ResourceBusy | std::io::ErrorKind::AddrInUse) => {
+                TransportOpenError::PortBusy(port)
+            }
+            _ => TransportOpenError::Other { port, error },
+        }
+    }
+}
+
+impl fmt::Display for TransportOpenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransportOpenError::NoSuchPort(port) => write!(f, "There is no port {}", port),
+            TransportOpenError::PermissionDenied(port) => write!(f, "Not allowed to open {}", port),
+            TransportOpenError::PortBusy(port) => write!(f, "{} is in use by another program", port),
+            TransportOpenError::BadAddress(port) => write!(f, "{} isn't a usable port address", port),
+            TransportOpenError::Other { port, error } => write!(f, "Couldn't open {}: {}", port, error),
+            TransportOpenError::Unreachable { address, error } => write!(f, "Couldn't connect to {}: {}", address, error),
+            TransportOpenError::Tls { address, error } => write!(f, "Couldn't set up TLS with {}: {}", address, error),
+        }
+    }
+}
+
+impl std::error::Error for TransportOpenError {}
+
+/// A page that read back differently from what was written.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PageMismatch {
+    pub address: PageAddress,
+    /// How many of the page's bytes differ.
+    pub differing: usize,
+    /// Offset of the first differing byte within the page.
+    pub first: usize,
+}
+
+#[derive(Debug)]
+pub enum FlasherError {
+    CouldntOpenTransport(TransportOpenError),
+    NoResponse,
+    Cancelled,
+    /// The device never answered the initial Hello.
+    DeviceUnreachable {
+        attempts: u32,
+    },
+    NoBaudRateAnswered {
+        rates: Vec<u32>,
+    },
+    CouldntSerialize {
+        command: &'static str,
+        field: Option<&'static str>,
+        error: postcard::Error,
+    },
+    CouldntDeserialize {
+        command: &'static str,
+        received: Vec<u8>,
+        error: postcard::Error,
+    },
+    CouldntReadInput(std::io::Error),
+    /// The batch manifest doesn't parse or describes an unusable device.
+    InvalidManifest(String),
+    CouldntWriteOutput(std::io::Error),
+    TransportFailed(std::io::Error),
+    DownloadFailed {
+        url: String,
+        reason: String,
+    },
+    /// The UF2 file ends partway through a block.
+    TruncatedUf2 {
+        length: usize,
+    },
+    InvalidBlock {
+        index: usize,
+        error: uftwo::BlockError,
+    },
+    UnsupportedBlockLength {
+        target_addr: u32,
+        data_len: u32,
+    },
+    OffsetAboveImage {
+        offset: u32,
+        lowest: u32,
+        highest: u64,
+    },
+    /// The input holds no pages to write, so a flash would only erase the slot.
+    EmptyImage,
+    TrailerTooLong {
+        length: usize,
+    },
+    UnalignedTrailer {
+        address: PageAddress,
+    },
+    TrailerOutOfRange {
+        address: PageAddress,
+    },
+    /// A --checksum-region reaches past the end of the image.
+    ChecksumRegionOutOfRange {
+        region: ChecksumRegion,
+        image_len: usize,
+    },
+    /// --pad-image-to isn't a whole number of pages.
+    UnalignedPadTarget {
+        pad_to: u32,
+    },
+    PadTargetTooSmall {
+        pad_to: u32,
+        image_len: usize,
+    },
+    UnalignedFooter {
+        address: PageAddress,
+    },
+    /// The footer would land in the image it describes.
+    FooterInsideImage {
+        address: PageAddress,
+        image_len: usize,
+    },
+    FooterOutOfRange {
+        address: PageAddress,
+    },
+    UnsupportedPageSize {
+        page_size: usize,
+    },
+    /// The program unit is zero or doesn't divide the page size.
+    UnsupportedProgramUnit {
+        program_unit: usize,
+        page_size: usize,
+    },
+    WindowNotCovered {
+        offset: u32,
+        end: u64,
+        missing: u64,
+    },
+    UnalignedWrite {
+        address: PageAddress,
+    },
+    OverlappingWrites {
+        first: PageAddress,
+        second: PageAddress,
+    },
+    /// The device answered with a response variant newer than this flasher.
+    UnknownResponse {
+        command: &'static str,
+        variant: u32,
+    },
+    UnexpectedResponse {
+        command: &'static str,
+        response: SerialFlasherResponse,
+    },
+    Nack {
+        command: &'static str,
+    },
+    /// Pages that read back wrong in a full verify, in address order.
+    VerifyFailed {
+        mismatches: Vec<PageMismatch>,
+    },
+    /// The whole flash ran out of page retries.
+    RetryBudgetExhausted {
+        retries: u32,
+    },
+    WrittenBytesMismatch {
+        written: usize,
+        expected: usize,
+    },
+    /// Pages that still failed after their retries, collected with `--keep-going`.
+    PagesFailed {
+        addresses: Vec<PageAddress>,
+    },
+    UnalignedOffset {
+        offset: u32,
+    },
+    InvalidFamilyId(u32),
+    ChecksumAlgorithmMismatch {
+        sent: ChecksumAlgorithm,
+        device: ChecksumAlgorithm,
+    },
+    ImageOutOfRange {
+        offset: u32,
+        length: usize,
+    },
+}
+
+impl FlasherError {
+    /// Whether this is one of the host-side sanity checks that `--force` turns into a warning.
+    /// Transport errors and anything the device answers with are never overridable.
+    pub fn is_overridable(&self) -> bool {
+        matches!(
+            self,
+            FlasherError::UnalignedWrite { .. } | FlasherError::OverlappingWrites { .. } | FlasherError::EmptyImage
+        )
+    }
+}
+
+impl fmt::Display for FlasherError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FlasherError::CouldntOpenTransport(e) => write!(f, "{}", e),
+            FlasherError::NoResponse => write!(f, "Didn't read a response"),
+            FlasherError::Cancelled => write!(f, "Cancelled"),
+            FlasherError::DeviceUnreachable { attempts } => {
+                write!(f, "The device didn't respond to Hello after {} attempt(s)", attempts)
+            }
+            FlasherError::NoBaudRateAnswered { rates } => {
+                let rates: Vec<String> = rates.iter().map(|r| r.to_string()).collect();
+                write!(f, "The device didn't answer Hello at any of {} baud", rates.join(", "))
+            }
+            FlasherError::CouldntSerialize { command, field: Some(field), error } => {
+                write!(f, "Couldn't serialize field `{}` of {}: {}", field, command, error)
+            }
+            FlasherError::CouldntSerialize { command, field: None, error } => {
+                write!(f, "Couldn't serialize {}: {}", command, error)
+            }
+            FlasherError::CouldntDeserialize { command, received, error } => {
+                write!(f, "Couldn't deserialize response to {}: {} (received {:?})", command, error, received)
+            }
+            FlasherError::CouldntReadInput(e) => write!(f, "Couldn't read the input file: {}", e),
+            FlasherError::InvalidManifest(e) => write!(f, "Invalid manifest: {}", e),
+            FlasherError::DownloadFailed { url, reason } => write!(f, "Couldn't download {}: {}", url, reason),
+            FlasherError::CouldntWriteOutput(e) => write!(f, "Couldn't write the output file: {}", e),
+            FlasherError::TransportFailed(e) => write!(f, "Serial I/O failed: {}", e),
+            FlasherError::InvalidBlock { index, error } => {
+                write!(f, "Couldn't parse UF2 block {}: {}", index, error)
+            }
+            FlasherError::TruncatedUf2 { length } => write!(
+                f,
+                "The UF2 file is {} bytes, which isn't a whole number of {} byte blocks. Was it cut off?",
+                length, UF2_BLOCK_SIZE
+            ),
+            FlasherError::UnsupportedBlockLength { target_addr, data_len } => {
+                write!(f, "Block at 0x{:08x} carries {} bytes, only {} byte blocks are supported", target_addr, data_len, PAGE_SIZE)
+            }
+            FlasherError::OffsetAboveImage { offset, lowest, highest } => write!(
+                f,
+                "Offset 0x{:08x} is above every block in the image, which covers 0x{:08x}..0x{:08x}. Is --offset set too high?",
+                offset, lowest, highest
+            ),
+            FlasherError::EmptyImage => write!(f, "The image holds nothing to write"),
+            FlasherError::TrailerTooLong { length } => {
+                write!(f, "The trailer is {} bytes, but it has to fit in one {} byte page", length, PAGE_SIZE)
+            }
+            FlasherError::UnalignedTrailer { address } => {
+                write!(f, "The trailer at {} isn't aligned to the {} byte page size", address, PAGE_SIZE)
+            }
+            FlasherError::TrailerOutOfRange { address } => {
+                write!(f, "The trailer at {} doesn't fit in the 32 bit address space", address)
+            }
+            FlasherError::ChecksumRegionOutOfRange { region, image_len } => write!(
+                f,
+                "The checksum region {} ends past the image, which is 0x{:x} bytes",
+                region, image_len
+            ),
+            FlasherError::UnalignedPadTarget { pad_to } => {
+                write!(f, "Can't pad the image to 0x{:x} bytes, that isn't a multiple of the {} byte page", pad_to, PAGE_SIZE)
+            }
+            FlasherError::PadTargetTooSmall { pad_to, image_len } => write!(
+                f,
+                "Can't pad the image to 0x{:x} bytes, it's already 0x{:x} bytes long",
+                pad_to, image_len
+            ),
+            FlasherError::UnalignedFooter { address } => {
+                write!(f, "The footer at {} doesn't start on a {} byte page boundary", address, PAGE_SIZE)
+            }
+            FlasherError::FooterInsideImage { address, image_len } => write!(
+                f,
+                "The footer at {} is inside the image, which is 0x{:x} bytes. Put it at or after the end",
+                address, image_len
+            ),
+            FlasherError::FooterOutOfRange { address } => {
+                write!(f, "The footer at {} doesn't fit in the 32 bit address space", address)
+            }
+            FlasherError::UnsupportedPageSize { page_size } => {
+                write!(f, "Pages of {} bytes aren't supported, the protocol only carries {} byte pages", page_size, PAGE_SIZE)
+            }
+            FlasherError::UnsupportedProgramUnit { program_unit, page_size } => write!(
+                f,
+                "A program unit of {} bytes doesn't evenly divide the {} byte page",
+                program_unit, page_size
+            ),
+            FlasherError::WindowNotCovered { offset, end, missing } => write!(
+                f,
+                "--offset and --length ask for 0x{:08x}..0x{:08x}, but the input has no data at 0x{:08x}. Is {} right?",
+                offset,
+                end,
+                missing,
+                if *missing == *offset as u64 { "--offset" } else { "--length" }
+            ),
+            FlasherError::UnalignedWrite { address } => {
+                write!(f, "Page write at {} isn't aligned to the {} byte page size", address, PAGE_SIZE)
+            }
+            FlasherError::OverlappingWrites { first, second } => {
+                write!(f, "Page writes at {} and {} overlap", first, second)
+            }
+            FlasherError::UnknownResponse { command, variant } => write!(
+                f,
+                "The device answered {} with response variant {}, which this flasher doesn't know. The device's firmware is probably newer, try updating the flasher",
+                command, variant
+            ),
+            FlasherError::UnexpectedResponse { command, response } => {
+                write!(f, "The device answered {} with {:?}, which doesn't make sense for it", command, response)
+            }
+            FlasherError::Nack { command } => write!(f, "The device sent a NACK in response to {}", command),
+            FlasherError::VerifyFailed { mismatches } => {
+                let differing: usize = mismatches.iter().map(|m| m.differing).sum();
+                write!(f, "{} page(s) read back wrong, {} bytes differ:", mismatches.len(), differing)?;
+
+                // Runs of adjacent pages are shown as one range, so a wrong sector stands out
+                // from scattered bit flips.
+                let mut rest = &mismatches[..];
+                while let Some(first) = rest.first() {
+                    let run = 1 + rest
+                        .windows(2)
+                        .take_while(|w| w[1].address.relative == w[0].address.relative + PAGE_SIZE)
+                        .count();
+                    let last = rest[run - 1].address;
+                    write!(
+                        f,
+                        "\n  {} .. 0x{:08x}: {} page(s), {} differing bytes, first at +0x{:x}",
+                        first.address,
+                        last.absolute() + PAGE_SIZE as u64,
+                        run,
+                        rest[..run].iter().map(|m| m.differing).sum::<usize>(),
+                        first.first
+                    )?;
+                    rest = &rest[run..];
+                }
+                Ok(())
+            }
+            FlasherError::RetryBudgetExhausted { retries } => write!(
+                f,
+                "Too many retries ({} across the flash), the link is unreliable",
+                retries
+            ),
+            FlasherError::WrittenBytesMismatch { written, expected } => write!(
+                f,
+                "Wrote {} bytes, but the input has {} bytes to flash. This is a bug in the flasher",
+                written, expected
+            ),
+            FlasherError::PagesFailed { addresses } => {
+                write!(f, "{} page(s) failed to write, at:", addresses.len())?;
+                for address in addresses {
+                    write!(f, "\n  {}", address)?;
+                }
+                Ok(())
+            }
+            FlasherError::UnalignedOffset { offset } => {
+                write!(f, "Offset 0x{:08x} isn't aligned to the {} byte page size", offset, PAGE_SIZE)
+            }
+            FlasherError::ChecksumAlgorithmMismatch { sent, device } => {
+                write!(f, "Sent a {:?} image checksum, but the device compared it as {:?}", sent, device)
+            }
+            FlasherError::InvalidFamilyId(id) => write!(f, "0x{:08x} isn't a valid UF2 family ID", id),
+            FlasherError::ImageOutOfRange { offset, length } => {
+                write!(f, "{} bytes at offset 0x{:08x} don't fit in the 32 bit address space", length, offset)
+            }
+        }
+    }
+}
+
+impl std::error::Error for FlasherError {}
+
+impl From<TransportOpenError> for FlasherError {
+    fn from(e: TransportOpenError) -> Self {
+        FlasherError::CouldntOpenTransport(e)
+    }
+}
@@ -0,0 +1,1076 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use postcard::from_bytes_cobs;
+use crate::error::{FlasherError, PageMismatch, TransportOpenError};
+use crate::image::{self, ChecksumRegion, Endian, ImageFormat, Pages, Trailer, ERASED_BYTE};
+use crate::timings::Timings;
+#[cfg(feature = "tls")]
+use crate::transport::TlsOptions;
+use crate::transport::Transport;
+use crate::protocol::{
+    ChecksumAlgorithm, ImageChecksum, Page, PageAddress, RelativeAddress, SerialFlasherCommand, SerialFlasherResponse, SessionOptions,
+    CRC8, NO_PAGE_CRC, PAGE_SIZE,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+    Handshake,
+    Erase,
+    Write,
+    Verify,
+    /// Sending MarkUpdated.
+    Mark,
+    Complete,
+}
+
+impl Phase {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Phase::Handshake => "handshake",
+            Phase::Erase => "erase",
+            Phase::Write => "write",
+            Phase::Verify => "verify",
+            Phase::Mark => "mark",
+            Phase::Complete => "complete",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Progress {
+    pub phase: Phase,
+    pub done: usize,
+    pub total: usize,
+}
+
+/// How the link held up: commands sent and the ones that didn't get a proper answer.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LinkStats {
+    pub commands: u32,
+    pub nacks: u32,
+    pub timeouts: u32,
+    /// Answers that didn't decode, or decoded to a response this flasher doesn't know.
+    pub decode_errors: u32,
+}
+
+impl LinkStats {
+    pub fn errors(&self) -> u32 {
+        self.nacks + self.timeouts + self.decode_errors
+    }
+
+    /// Errors per command sent, between 0 and 1.
+    pub fn error_rate(&self) -> f64 {
+        if self.commands == 0 {
+            0.0
+        } else {
+            self.errors() as f64 / self.commands as f64
+        }
+    }
+}
+
+/// How a flash is checked after writing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VerifyMode {
+    /// The device compares a checksum over the whole image.
+    Checksum,
+    /// Every page is read back and compared first, then the checksum. Slow, but pinpoints what's
+    /// wrong.
+    Full,
+}
+
+impl std::str::FromStr for VerifyMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "checksum" => Ok(VerifyMode::Checksum),
+            "full" => Ok(VerifyMode::Full),
+            _ => Err(format!("Unknown verify mode `{}`, expected checksum or full", s)),
+        }
+    }
+}
+
+/// How to flash one image.
+#[derive(Debug, Clone)]
+pub struct FlashOptions {
+    /// Absolute address the update slot starts at. Relative addresses on the wire count from here.
+    pub offset: u32,
+    /// Bytes per WritePage. Only [`PAGE_SIZE`] is supported by the protocol for now.
+    pub page_size: usize,
+    /// What the device's erased flash reads back as, for padding and the gaps between pages.
+    pub fill_byte: u8,
+    /// Write erased pages wherever the image leaves a gap below this many bytes, so the slot is
+    /// programmed up to there. A multiple of the page size, at least the image's length.
+    pub pad_to: Option<u32>,
+    /// Smallest unit the device programs. A short last page is padded to a multiple of this
+    /// rather than to the full page. Must divide `page_size`.
+    pub program_unit: usize,
+    pub format: ImageFormat,
+    /// Which checksum CompareChecksum sends over the whole image.
+    pub image_checksum: ChecksumAlgorithm,
+    /// Limit the final checksum to these windows, one compare each, instead of the whole image.
+    pub checksum_regions: Vec<ChecksumRegion>,
+    pub verify: VerifyMode,
+    /// Number of bytes from `offset` the image must cover, if known.
+    pub length: Option<u32>,
+    /// Extra pages written after the image, each holding some text.
+    pub trailers: Vec<Trailer>,
+    /// Write a footer page with the length and checksum of everything before it here, see
+    /// [`image::footer_page`] for the layout.
+    pub footer: Option<RelativeAddress>,
+    /// Byte order of multi-byte metadata stamped into pages, never of the image itself.
+    pub endian: Endian,
+    /// Send a Hello this often while waiting for the erase in PrepareForUpdate to finish.
+    pub keepalive: Option<Duration>,
+    /// Turn the overridable image checks (see [`FlasherError::is_overridable`]) into warnings.
+    pub force: bool,
+}
+
+impl FlashOptions {
+    pub fn new(offset: u32) -> Self {
+        FlashOptions {
+            offset,
+            page_size: PAGE_SIZE,
+            fill_byte: ERASED_BYTE,
+            pad_to: None,
+            program_unit: PAGE_SIZE,
+            format: ImageFormat::Uf2,
+            image_checksum: ChecksumAlgorithm::Sha256,
+            checksum_regions: Vec::new(),
+            verify: VerifyMode::Checksum,
+            length: None,
+            trailers: Vec::new(),
+            footer: None,
+            endian: Endian::Little,
+            keepalive: None,
+            force: false,
+        }
+    }
+}
+
+/// The page writes and checks for an image, worked out and checked against its [`FlashOptions`]
+/// before anything is sent. Made with [`Flasher::plan`] and sent with [`Flasher::flash_plan`], so
+/// a large image is only parsed and checksummed once however often it's looked at in between.
+/// The pages of the image aren't copied: the plan keeps where they are in the input and borrows
+/// it, and each WritePage is put together as it's sent.
+pub struct Plan<'a> {
+    pages: Pages<'a>,
+    /// The CompareChecksum or CompareRegionChecksums that end the flash.
+    checks: Vec<SerialFlasherCommand>,
+    /// The errors of the failed checks that `force` overrode.
+    overridden: Vec<FlasherError>,
+    /// How many bytes the flash should write, counted from the input instead of from `pages`
+    /// so the two can be checked against each other.
+    expected_bytes: usize,
+    opts: FlashOptions,
+}
+
+impl Plan<'_> {
+    /// The relative address and length of every page, in the order they will be sent.
+    pub fn writes(&self) -> Vec<(RelativeAddress, usize)> {
+        self.pages.addresses().map(|address| (address, PAGE_SIZE)).collect()
+    }
+
+    /// The checksum of the whole image, also when the flash only checks regions of it.
+    pub fn checksum(&self) -> ImageChecksum {
+        match self.checks.as_slice() {
+            [SerialFlasherCommand::CompareChecksum(_, checksum)] => *checksum,
+            _ => image::flashed_checksum(&self.pages, 0..self.pages.written_len(), self.opts.image_checksum),
+        }
+    }
+
+    /// The CompareChecksum, or CompareRegionChecksums with `checksum_regions`, that the flash
+    /// ends with.
+    pub fn checks(&self) -> &[SerialFlasherCommand] {
+        &self.checks
+    }
+}
+
+/// Turns the image into page writes and checks them against `opts`.
+fn plan<'a>(data: &'a [u8], opts: &FlashOptions) -> Result<Plan<'a>, FlasherError> {
+    if opts.page_size != PAGE_SIZE {
+        return Err(FlasherError::UnsupportedPageSize { page_size: opts.page_size });
+    }
+    if opts.program_unit == 0 || !opts.page_size.is_multiple_of(opts.program_unit) {
+        return Err(FlasherError::UnsupportedProgramUnit {
+            program_unit: opts.program_unit,
+            page_size: opts.page_size,
+        });
+    }
+
+    let (mut pages, mut expected_bytes) = match opts.format {
+        ImageFormat::Uf2 => {
+            let pages = Pages::uf2(data, opts.offset, opts.fill_byte)?;
+            if let Some(length) = opts.length {
+                image::check_coverage(image::uf2_ranges(data), opts.offset, length)?;
+            }
+            (pages, image::uf2_payload_len(data, opts.offset))
+        }
+        ImageFormat::Bin => {
+            if let Some(length) = opts.length.filter(|&l| l as usize > data.len()) {
+                return Err(FlasherError::WindowNotCovered {
+                    offset: opts.offset,
+                    end: opts.offset as u64 + length as u64,
+                    missing: opts.offset as u64 + data.len() as u64,
+                });
+            }
+            (Pages::bin(data, opts.program_unit, opts.fill_byte), data.len().div_ceil(PAGE_SIZE) * PAGE_SIZE)
+        }
+    };
+    let mut overridden = Vec::new();
+    let empty = if pages.is_empty() { Err(FlasherError::EmptyImage) } else { Ok(()) };
+    overridable(empty, opts.force, &mut overridden)?;
+
+    let mut trailers = Vec::new();
+    for trailer in &opts.trailers {
+        trailers.push((trailer.address, image::trailer_page(trailer, opts.offset)?));
+    }
+
+    if let Some(pad_to) = opts.pad_to {
+        if !(pad_to as usize).is_multiple_of(PAGE_SIZE) {
+            return Err(FlasherError::UnalignedPadTarget { pad_to });
+        }
+        let image_len = pages.written_len();
+        if image_len > pad_to as usize {
+            return Err(FlasherError::PadTargetTooSmall { pad_to, image_len });
+        }
+
+        let reserved: Vec<RelativeAddress> = trailers.iter().map(|&(address, _)| address).collect();
+        expected_bytes += pages.pad(pad_to as usize, &reserved) * PAGE_SIZE;
+    }
+
+    expected_bytes += trailers.len() * PAGE_SIZE;
+    for (address, page) in trailers {
+        pages.push(address, page);
+    }
+
+    if let Some(address) = opts.footer {
+        let footer = image::footer_page(&pages, address, opts.offset, opts.image_checksum, opts.endian)?;
+        pages.push(address, footer);
+        expected_bytes += PAGE_SIZE;
+    }
+
+    let image_len = pages.written_len();
+    if let Some(region) = opts.checksum_regions.iter().find(|r| r.end() > image_len) {
+        return Err(FlasherError::ChecksumRegionOutOfRange { region: *region, image_len });
+    }
+
+    overridable(image::check_write_addresses(pages.addresses(), opts.offset), opts.force, &mut overridden)?;
+
+    let checks = image::checksum_commands(&pages, &opts.checksum_regions, opts.image_checksum);
+
+    Ok(Plan { pages, checks, overridden, expected_bytes, opts: opts.clone() })
+}
+
+/// Passes on the result of a check, unless `force` turns its error into a warning kept in
+/// `overridden`.
+fn overridable(res: Result<(), FlasherError>, force: bool, overridden: &mut Vec<FlasherError>) -> Result<(), FlasherError> {
+    match res {
+        Err(e) if force && e.is_overridable() => {
+            overridden.push(e);
+            Ok(())
+        }
+        res => res,
+    }
+}
+
+/// How long to wait for a response, and for a TCP connection to come up.
+const DEFAULT_TIMEOUT: Duration = Duration::from_millis(10000);
+
+/// Bytes per write when trickling frames out for devices with small receive buffers.
+const DEFAULT_CHUNK_SIZE: usize = 16;
+
+pub struct Flasher {
+    port: Box<dyn Transport>,
+    timeout: Duration,
+    /// Bytes read from the port that don't make up a complete frame yet.
+    received: Vec<u8>,
+    dump_sent: Option<BufWriter<File>>,
+    chunk_size: Option<NonZeroUsize>,
+    frame_aligned: bool,
+    hello_attempts: u32,
+    flush_before_hello: bool,
+    /// Whether anything was sent since opening, so stale input is only drained before the first command.
+    sent_any: bool,
+    retry_on_nack: u32,
+    retry_on_timeout: u32,
+    retry_on_decode: u32,
+    keep_going: bool,
+    recover_reset: bool,
+    /// Retries allowed across all pages of a flash, on top of the per-page limits.
+    max_total_retries: Option<u32>,
+    retries_used: u32,
+    stats: LinkStats,
+    page_crc: bool,
+    bare_checksum_ack: bool,
+    progress: Option<Box<dyn FnMut(Progress)>>,
+    phase: Option<Phase>,
+    timings: Option<Timings>,
+    /// Offset of the flash in progress, for showing absolute addresses.
+    offset: u32,
+    cancel: Option<Arc<AtomicBool>>,
+}
+
+impl Flasher {
+    pub fn open(path: &str, baud_rate: u32) -> Result<Self, TransportOpenError> {
+        let port = serialport::new(path, baud_rate)
+            .timeout(DEFAULT_TIMEOUT)
+            .open()
+            .map_err(|e| TransportOpenError::from_serialport(path, e))?;
+
+        Ok(Self::from_transport(port))
+    }
+
+    /// Talks to a bootloader behind a TCP to serial bridge.
+    pub fn connect(address: SocketAddr) -> Result<Self, TransportOpenError> {
+        let unreachable = |error| TransportOpenError::Unreachable { address: address.to_string(), error };
+        let stream = TcpStream::connect_timeout(&address, DEFAULT_TIMEOUT).map_err(unreachable)?;
+        stream.set_nodelay(true).map_err(unreachable)?;
+
+        Ok(Self::from_transport(stream))
+    }
+
+    /// Like [`Flasher::connect`], with the connection wrapped in TLS.
+    #[cfg(feature = "tls")]
+    pub fn connect_tls(address: SocketAddr, tls: &TlsOptions) -> Result<Self, TransportOpenError> {
+        let stream = tls
+            .connect(address, DEFAULT_TIMEOUT)
+            .map_err(|error| TransportOpenError::Tls { address: address.to_string(), error })?;
+
+        Ok(Self::from_transport(stream))
+    }
+
+    /// Flashes over a connection the caller opened, and may keep using once the flasher is
+    /// dropped, like a port shared with a serial monitor.
+    pub fn from_transport(transport: impl Transport + 'static) -> Self {
+        Flasher {
+            port: Box::new(transport),
+            timeout: DEFAULT_TIMEOUT,
+            received: Vec::new(),
+            dump_sent: None,
+            chunk_size: None,
+            frame_aligned: false,
+            hello_attempts: 1,
+            flush_before_hello: true,
+            sent_any: false,
+            retry_on_nack: 0,
+            retry_on_timeout: 0,
+            retry_on_decode: 0,
+            keep_going: false,
+            recover_reset: false,
+            max_total_retries: None,
+            retries_used: 0,
+            stats: LinkStats::default(),
+            page_crc: true,
+            bare_checksum_ack: false,
+            progress: None,
+            phase: None,
+            timings: None,
+            offset: 0,
+            cancel: None,
+        }
+    }
+
+    /// Switches the port to another baud rate, dropping whatever was received at the old one.
+    pub fn set_baud_rate(&mut self, baud_rate: u32) -> Result<(), FlasherError> {
+        self.port
+            .set_baud_rate(baud_rate)
+            .and_then(|_| self.port.clear_input())
+            .map_err(FlasherError::TransportFailed)?;
+        self.received.clear();
+        Ok(())
+    }
+
+    /// How long to wait for a response. 10 seconds unless set otherwise.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Appends every frame sent from now on to the file at `path`, exactly as it goes out on the
+    /// wire. Each frame is preceded by a header: the length of the command name (u8), the command
+    /// name in ASCII, and the length of the frame (u32, little endian).
+    pub fn dump_sent_to(&mut self, path: &Path) -> std::io::Result<()> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        self.dump_sent = Some(BufWriter::new(file));
+        Ok(())
+    }
+
+    /// Sends the initial Hello up to `attempts` times before giving up on the device, for devices
+    /// that are still booting when we start talking to them.
+    pub fn set_hello_attempts(&mut self, attempts: u32) {
+        self.hello_attempts = attempts.max(1);
+    }
+
+    /// Throw away whatever is waiting on the port before the first command of the session and
+    /// before every Hello, such as the rest of an aborted session, so it isn't taken for the
+    /// answer. On unless set otherwise.
+    pub fn set_flush_before_hello(&mut self, flush: bool) {
+        self.flush_before_hello = flush;
+    }
+
+    /// Largest write to the transport. Frames are split into writes of 16 bytes with a pause
+    /// after each unless set otherwise.
+    pub fn set_chunk_size(&mut self, chunk_size: NonZeroUsize) {
+        self.chunk_size = Some(chunk_size);
+    }
+
+    /// Write every frame in one go, without pauses, and only split it if it's bigger than the
+    /// chunk size. For devices that read whole frames at a time.
+    pub fn set_frame_aligned(&mut self, frame_aligned: bool) {
+        self.frame_aligned = frame_aligned;
+    }
+
+    /// How many times a page write is resent after the device NACKs it, after it doesn't answer at
+    /// all, and after its answer doesn't decode.
+    pub fn set_page_retries(&mut self, on_nack: u32, on_timeout: u32, on_decode: u32) {
+        self.retry_on_nack = on_nack;
+        self.retry_on_timeout = on_timeout;
+        self.retry_on_decode = on_decode;
+    }
+
+    /// Keep writing the remaining pages after a page has run out of retries, and report every
+    /// failed page at the end instead of only the first.
+    pub fn set_keep_going(&mut self, keep_going: bool) {
+        self.keep_going = keep_going;
+    }
+
+    /// If the first page after the erase is NACKed, assume the erase reset the device: handshake
+    /// again, resume with PrepareForUpdateNoErase and resend the page, once per flash. Off by
+    /// default, since a device NACKing its first page for other reasons gets a second try too.
+    pub fn set_recover_reset(&mut self, recover_reset: bool) {
+        self.recover_reset = recover_reset;
+    }
+
+    /// Fail the flash once this many page retries have been spent in total, whatever the
+    /// per-page limits still allow.
+    pub fn set_max_total_retries(&mut self, max_total_retries: Option<u32>) {
+        self.max_total_retries = max_total_retries;
+    }
+
+    /// Page retries spent by the last flash.
+    pub fn retries_used(&self) -> u32 {
+        self.retries_used
+    }
+
+    /// Commands and failed answers since the last flash started, or since opening.
+    pub fn link_stats(&self) -> LinkStats {
+        self.stats
+    }
+
+    /// Whether pages carry their CRC8 for the device to check. Turning it off is negotiated in the
+    /// Hello, so a device that can't skip the check refuses the session instead of NACKing every page.
+    pub fn set_page_crc(&mut self, page_crc: bool) {
+        self.page_crc = page_crc;
+    }
+
+    /// Take a plain Ack to a checksum compare as a pass, for devices that predate saying which
+    /// algorithm they compared. Off by default, since any stray Ack would then pass the compare.
+    pub fn set_accept_bare_checksum_ack(&mut self, accept: bool) {
+        self.bare_checksum_ack = accept;
+    }
+
+    /// Calls `progress` at the start and end of every phase of a flash, and after every page.
+    pub fn on_progress(&mut self, progress: impl FnMut(Progress) + 'static) {
+        self.progress = Some(Box::new(progress));
+    }
+
+    /// Stops a flash before its next page write, read back or checksum compare once `cancel` is
+    /// set, aborting the update on the device instead of marking it updated.
+    /// Meant to be set from a Ctrl-C handler.
+    pub fn set_cancel(&mut self, cancel: Arc<AtomicBool>) {
+        self.cancel = Some(cancel);
+    }
+
+    /// Starts recording the round trip time of every command and how long the erase takes.
+    pub fn record_timings(&mut self) {
+        self.timings = Some(Timings::default());
+    }
+
+    /// What has been recorded since [`Flasher::record_timings`].
+    pub fn timings(&self) -> Option<&Timings> {
+        self.timings.as_ref()
+    }
+
+    /// The phase the last flash got to, which is where it failed if it did. `None` if it stopped
+    /// before talking to the device, e.g. on an image that doesn't parse.
+    pub fn last_phase(&self) -> Option<Phase> {
+        self.phase
+    }
+
+    fn report(&mut self, phase: Phase, done: usize, total: usize) {
+        self.phase = Some(phase);
+        if let Some(progress) = &mut self.progress {
+            progress(Progress { phase, done, total });
+        }
+    }
+
+    /// Works out what flashing `data` with `opts` would send. Runs all the checks on the image
+    /// that a flash does, without a warning for those overridden by `opts.force`. No transport is
+    /// involved.
+    pub fn plan<'a>(data: &'a [u8], opts: &FlashOptions) -> Result<Plan<'a>, FlasherError> {
+        plan(data, opts)
+    }
+
+    /// Returns the relative address and length of every page flashing `data` with `opts` would
+    /// write, in the order they would be sent. See [`Plan::writes`].
+    pub fn planned_writes(data: &[u8], opts: &FlashOptions) -> Result<Vec<(RelativeAddress, usize)>, FlasherError> {
+        Ok(plan(data, opts)?.writes())
+    }
+
+    /// The checksum a flash of `data` with `opts` would ask the device to compare.
+    pub fn planned_checksum(data: &[u8], opts: &FlashOptions) -> Result<ImageChecksum, FlasherError> {
+        Ok(plan(data, opts)?.checksum())
+    }
+
+    /// The CompareChecksum, or CompareRegionChecksums with `opts.checksum_regions`, that a flash
+    /// of `data` with `opts` would end with.
+    pub fn planned_checks(data: &[u8], opts: &FlashOptions) -> Result<Vec<SerialFlasherCommand>, FlasherError> {
+        Ok(plan(data, opts)?.checks)
+    }
+
+    /// Flashes the image in `data`, which is parsed according to `opts.format`.
+    pub fn flash_bytes(&mut self, data: &[u8], opts: FlashOptions) -> Result<(), FlasherError> {
+        let plan = plan(data, &opts)?;
+        self.flash_plan(plan)
+    }
+
+    /// Flashes an image planned with [`Flasher::plan`].
+    pub fn flash_plan(&mut self, plan: Plan<'_>) -> Result<(), FlasherError> {
+        self.phase = None;
+        self.offset = plan.opts.offset;
+        self.retries_used = 0;
+        self.stats = LinkStats::default();
+        for e in &plan.overridden {
+            note!("Warning: {} (continuing because of --force)", e);
+        }
+
+        self.report(Phase::Handshake, 0, 1);
+        self.hello()?;
+        self.report(Phase::Handshake, 1, 1);
+
+        let result = self.update(&plan);
+        if let Err(e) = &result {
+            self.abort(e);
+        }
+        result
+    }
+
+    /// Everything after the Hello: erases, writes the pages, verifies and marks the update.
+    fn update(&mut self, plan: &Plan<'_>) -> Result<(), FlasherError> {
+        let (checks, opts) = (&plan.checks, &plan.opts);
+        self.report(Phase::Erase, 0, 1);
+        let erase_start = Instant::now();
+        let resp = self.prepare_for_update(opts.keepalive)?;
+        if let Some(timings) = &mut self.timings {
+            timings.record_erase(erase_start.elapsed());
+        }
+        match resp {
+            SerialFlasherResponse::Ack => {}
+            SerialFlasherResponse::Nack => return Err(FlasherError::Nack { command: "PrepareForUpdate" }),
+            response => return Err(FlasherError::UnexpectedResponse { command: "PrepareForUpdate", response }),
+        }
+        self.report(Phase::Erase, 1, 1);
+
+        let total = plan.pages.len();
+        self.report(Phase::Write, 0, total);
+
+        let mut failed = Vec::new();
+        let mut written_bytes = 0;
+        for done in 0..total {
+            if self.cancelled() {
+                return Err(FlasherError::Cancelled);
+            }
+            let (command, address) = self.page_command(&plan.pages, done);
+
+            let mut result = self.write_page(&command, address);
+            if self.recover_reset
+                && written_bytes == 0
+                && failed.is_empty()
+                && matches!(result, Err(FlasherError::Nack { .. }))
+            {
+                note!("The first page was NACKed right after the erase, the device may have reset. Resuming the update");
+                self.resume_after_reset()?;
+                result = self.write_page(&command, address);
+            }
+
+            match result {
+                Ok(()) => written_bytes += PAGE_SIZE,
+                Err(
+                    e @ (FlasherError::Nack { .. }
+                    | FlasherError::NoResponse
+                    | FlasherError::CouldntDeserialize { .. }
+                    | FlasherError::UnknownResponse { .. }),
+                ) if self.keep_going =>
+                {
+                    note!("Page @ {} failed, continuing with the next one: {}", address, e);
+                    failed.push(address);
+                }
+                Err(e) => return Err(e),
+            }
+
+            self.report(Phase::Write, done + 1, total);
+        }
+
+        if !failed.is_empty() {
+            return Err(FlasherError::PagesFailed { addresses: failed });
+        }
+
+        // A self-check: if the planning dropped pages, don't let the device boot the result.
+        if written_bytes != plan.expected_bytes {
+            return Err(FlasherError::WrittenBytesMismatch { written: written_bytes, expected: plan.expected_bytes });
+        }
+
+        self.expect_ack(SerialFlasherCommand::FinishedWriting)?;
+
+        if opts.verify == VerifyMode::Full {
+            self.verify_pages(&plan.pages)?;
+        }
+
+        self.report(Phase::Verify, 0, checks.len());
+        for (done, check) in checks.iter().enumerate() {
+            if self.cancelled() {
+                return Err(FlasherError::Cancelled);
+            }
+            self.compare_checksum(check)?;
+            self.report(Phase::Verify, done + 1, checks.len());
+        }
+
+        if self.cancelled() {
+            return Err(FlasherError::Cancelled);
+        }
+        self.report(Phase::Mark, 0, 1);
+        self.expect_ack(SerialFlasherCommand::MarkUpdated)?;
+        self.report(Phase::Mark, 1, 1);
+
+        self.report(Phase::Complete, 1, 1);
+
+        Ok(())
+    }
+
+    /// Handshakes again with a device that reset and picks the update back up without erasing.
+    fn resume_after_reset(&mut self) -> Result<(), FlasherError> {
+        self.hello()?;
+        self.expect_ack(SerialFlasherCommand::PrepareForUpdateNoErase)
+    }
+
+    /// Sends `cmd` and fails unless the device ACKs it.
+    fn expect_ack(&mut self, cmd: SerialFlasherCommand) -> Result<(), FlasherError> {
+        match self.request(&cmd)? {
+            SerialFlasherResponse::Ack => Ok(()),
+            SerialFlasherResponse::Nack => Err(FlasherError::Nack { command: cmd.name() }),
+            response => Err(FlasherError::UnexpectedResponse { command: cmd.name(), response }),
+        }
+    }
+
+    /// Reads back every written page and compares it, collecting all the pages that differ.
+    fn verify_pages(&mut self, pages: &Pages) -> Result<(), FlasherError> {
+        let mut mismatches = Vec::new();
+        let total = pages.len();
+        self.report(Phase::Verify, 0, total);
+
+        for done in 0..total {
+            if self.cancelled() {
+                return Err(FlasherError::Cancelled);
+            }
+
+            let (address, page) = pages.get(done);
+            let read = self.read_page(address)?;
+            let differing: Vec<usize> = (0..PAGE_SIZE).filter(|&i| read[i] != page[i]).collect();
+            if let Some(&first) = differing.first() {
+                let address = PageAddress::new(address, self.offset);
+                note!("Page @ {} read back with {} differing bytes", address, differing.len());
+                mismatches.push(PageMismatch { address, differing: differing.len(), first });
+            }
+
+            self.report(Phase::Verify, done + 1, total);
+        }
+
+        if mismatches.is_empty() {
+            Ok(())
+        } else {
+            mismatches.sort_by_key(|m| m.address.relative);
+            Err(FlasherError::VerifyFailed { mismatches })
+        }
+    }
+
+    /// The WritePage for the `index`th of `pages`, put together just before it's sent, and where it
+    /// goes. The page's CRC8 is left out unless pages carry one.
+    fn page_command(&self, pages: &Pages, index: usize) -> (SerialFlasherCommand, PageAddress) {
+        let (address, page) = pages.get(index);
+        let checksum = if self.page_crc { CRC8.checksum(&page[..]) } else { NO_PAGE_CRC };
+        (SerialFlasherCommand::WritePage(address, page, checksum), PageAddress::new(address, self.offset))
+    }
+
+    /// Reads the current contents of the page at `address`.
+    pub fn read_page(&mut self, address: RelativeAddress) -> Result<Page, FlasherError> {
+        match self.send_command(SerialFlasherCommand::ReadPage(address))? {
+            SerialFlasherResponse::PageData(page) => Ok(*page),
+            SerialFlasherResponse::Nack => Err(FlasherError::Nack { command: "ReadPage" }),
+            response => Err(FlasherError::UnexpectedResponse { command: "ReadPage", response }),
+        }
+    }
+
+    /// Tells the device to throw away the partial update after `error`, so it never boots it. The
+    /// connection may well be gone by now, so this is best effort and its own failures are only
+    /// logged.
+    fn abort(&mut self, error: &FlasherError) {
+        note!("Aborting the update after: {}", error);
+        match self.request(&SerialFlasherCommand::Abort) {
+            Ok(SerialFlasherResponse::Nack) => note!("The device NACKed the Abort"),
+            Ok(_) => {}
+            Err(e) => note!("Couldn't abort the update: {}", e),
+        }
+    }
+
+    fn cancelled(&self) -> bool {
+        self.cancel.as_ref().is_some_and(|c| c.load(Ordering::Relaxed))
+    }
+
+    /// Greets the device, giving each attempt more time than the one before. The last attempt gets
+    /// the full response timeout.
+    pub fn hello(&mut self) -> Result<(), FlasherError> {
+        if self.flush_before_hello {
+            self.discard_input()?;
+        }
+
+        let cmd = self.hello_command();
+
+        for attempt in 1..=self.hello_attempts {
+            if attempt > 1 {
+                self.discard_input()?;
+            }
+            let timeout = self.timeout * attempt / self.hello_attempts;
+            let start = Instant::now();
+            self.write_command(&cmd)?;
+
+            let resp = self.read_response_until(&cmd, start + timeout);
+            if let (Some(timings), Ok(_)) = (&mut self.timings, &resp) {
+                timings.record(cmd.name(), start.elapsed());
+            }
+
+            match resp {
+                Ok(SerialFlasherResponse::Nack) => return Err(FlasherError::Nack { command: cmd.name() }),
+                Ok(_) => {
+                    self.drop_late_answers(&cmd, attempt - 1, start.elapsed());
+                    return Ok(());
+                }
+                // Line noise, like answers at the wrong baud rate, mostly decodes as an unknown response.
+                Err(
+                    e @ (FlasherError::NoResponse
+                    | FlasherError::CouldntDeserialize { .. }
+                    | FlasherError::UnknownResponse { .. }),
+                ) => {
+                    note!("Hello attempt {} of {} failed: {}", attempt, self.hello_attempts, e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        Err(FlasherError::DeviceUnreachable { attempts: self.hello_attempts })
+    }
+
+    /// Throws away everything received so far, such as the rest of an answer that came too late.
+    fn discard_input(&mut self) -> Result<(), FlasherError> {
+        self.port.clear_input().map_err(FlasherError::TransportFailed)?;
+        self.received.clear();
+        Ok(())
+    }
+
+    /// After a resent `cmd` got an answer, drops up to `resends` more that arrive within `quiet`.
+    /// A device that was only slow answers every send in turn, so the answer just read can belong
+    /// to an earlier send, and the next command would otherwise read the one left over.
+    fn drop_late_answers(&mut self, cmd: &SerialFlasherCommand, resends: u32, quiet: Duration) {
+        for _ in 0..resends {
+            match self.read_frame_until(cmd, Instant::now() + quiet) {
+                Ok(resp) => note!("Dropped a late answer to an earlier {}: {:?}", cmd.name(), resp),
+                Err(_) => break,
+            }
+        }
+    }
+
+    /// The Hello that opens this session. Keepalives repeat it so they don't reset the options.
+    fn hello_command(&self) -> SerialFlasherCommand {
+        if self.page_crc {
+            SerialFlasherCommand::Hello
+        } else {
+            SerialFlasherCommand::HelloWithOptions(SessionOptions { page_crc: false })
+        }
+    }
+
+    /// Sends a Ping and returns how long it took to get the Ack back.
+    pub fn ping(&mut self) -> Result<Duration, FlasherError> {
+        let start = Instant::now();
+
+        match self.send_command(SerialFlasherCommand::Ping)? {
+            SerialFlasherResponse::Nack => Err(FlasherError::Nack { command: "Ping" }),
+            _ => Ok(start.elapsed()),
+        }
+    }
+
+    /// Fetches the device's diagnostic log.
+    pub fn get_log(&mut self) -> Result<String, FlasherError> {
+        match self.send_command(SerialFlasherCommand::GetLog)? {
+            SerialFlasherResponse::Log(log) => Ok(log),
+            SerialFlasherResponse::Nack => Err(FlasherError::Nack { command: "GetLog" }),
+            response => Err(FlasherError::UnexpectedResponse { command: "GetLog", response }),
+        }
+    }
+
+    pub fn send_command(&mut self, cmd: SerialFlasherCommand) -> Result<SerialFlasherResponse, FlasherError> {
+        self.request(&cmd)
+    }
+
+    fn request(&mut self, cmd: &SerialFlasherCommand) -> Result<SerialFlasherResponse, FlasherError> {
+        let start = Instant::now();
+        self.write_command(cmd)?;
+        let resp = self.read_response(cmd);
+
+        if let (Some(timings), Ok(_)) = (&mut self.timings, &resp) {
+            timings.record(cmd.name(), start.elapsed());
+        }
+        resp
+    }
+
+    /// Sends a WritePage, resending it on NACKs, timeouts and undecodable answers until the
+    /// respective retries run out.
+    /// Resends are the identical command, which the device ACKs without reprogramming if the first
+    /// one got through and only its Ack was lost. See [`SerialFlasherCommand::WritePage`].
+    fn write_page(&mut self, cmd: &SerialFlasherCommand, address: PageAddress) -> Result<(), FlasherError> {
+        let mut nacks = 0;
+        let mut timeouts = 0;
+        let mut garbled = 0;
+
+        loop {
+            let start = Instant::now();
+            match self.request(cmd) {
+                Ok(SerialFlasherResponse::Nack) if nacks < self.retry_on_nack => {
+                    self.spend_retry()?;
+                    nacks += 1;
+                    note!("Page write @ {} was NACKed, retrying ({}/{})", address, nacks, self.retry_on_nack);
+                }
+                Ok(SerialFlasherResponse::Nack) => return Err(FlasherError::Nack { command: cmd.name() }),
+                Ok(_) => {
+                    // Resends after timeouts are the lost-Ack case, so their answers may trail in.
+                    self.drop_late_answers(cmd, timeouts, start.elapsed());
+                    return Ok(());
+                }
+                Err(FlasherError::NoResponse) if timeouts < self.retry_on_timeout => {
+                    self.spend_retry()?;
+                    self.discard_input()?;
+                    timeouts += 1;
+                    note!("Page write @ {} timed out, retrying ({}/{})", address, timeouts, self.retry_on_timeout);
+                }
+                // Line noise can look like a response from the future too.
+                Err(e @ (FlasherError::CouldntDeserialize { .. } | FlasherError::UnknownResponse { .. }))
+                    if garbled < self.retry_on_decode =>
+                {
+                    self.spend_retry()?;
+                    garbled += 1;
+                    note!("{} for page @ {}, retrying ({}/{})", e, address, garbled, self.retry_on_decode);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Takes one retry from the session's budget, failing once it's used up.
+    fn spend_retry(&mut self) -> Result<(), FlasherError> {
+        if self.max_total_retries.is_some_and(|max| self.retries_used >= max) {
+            return Err(FlasherError::RetryBudgetExhausted { retries: self.retries_used });
+        }
+        self.retries_used += 1;
+        Ok(())
+    }
+
+    /// Sends a CompareChecksum or CompareRegionChecksum.
+    fn compare_checksum(&mut self, cmd: &SerialFlasherCommand) -> Result<(), FlasherError> {
+        let sent = match cmd {
+            SerialFlasherCommand::CompareChecksum(_, checksum)
+            | SerialFlasherCommand::CompareRegionChecksum(_, _, checksum) => checksum.algorithm(),
+            _ => unreachable!("{} isn't a checksum compare", cmd.name()),
+        };
+
+        match self.request(cmd)? {
+            SerialFlasherResponse::ChecksumAck(device) if device == sent => Ok(()),
+            SerialFlasherResponse::ChecksumAck(device) => Err(FlasherError::ChecksumAlgorithmMismatch { sent, device }),
+            SerialFlasherResponse::Ack if self.bare_checksum_ack => {
+                note!("Warning: the device acknowledged the checksum without saying which algorithm it compared");
+                Ok(())
+            }
+            SerialFlasherResponse::Nack => Err(FlasherError::Nack { command: cmd.name() }),
+            response => Err(FlasherError::UnexpectedResponse { command: cmd.name(), response }),
+        }
+    }
+
+    /// Sends PrepareForUpdate, optionally keeping the device alive with a Hello whenever it has been
+    /// quiet for `keepalive`. The Acks to those Hellos are swallowed, and the erase counts as failed if
+    /// any of the responses is a Nack.
+    fn prepare_for_update(&mut self, keepalive: Option<Duration>) -> Result<SerialFlasherResponse, FlasherError> {
+        let Some(keepalive) = keepalive else {
+            return self.send_command(SerialFlasherCommand::PrepareForUpdate);
+        };
+
+        let cmd = SerialFlasherCommand::PrepareForUpdate;
+        self.write_command(&cmd)?;
+
+        let deadline = Instant::now() + self.timeout;
+        let mut outstanding = 1;
+        let mut result = SerialFlasherResponse::Ack;
+
+        while outstanding > 0 {
+            // Running into the keepalive interval is what's expected while the device erases, so
+            // only the other outcomes are counted in the link stats.
+            let resp = self.read_frame_until(&cmd, deadline.min(Instant::now() + keepalive));
+            if matches!(resp, Err(FlasherError::NoResponse)) && Instant::now() < deadline {
+                self.write_command(&self.hello_command())?;
+                outstanding += 1;
+                continue;
+            }
+
+            self.tally(&resp);
+            outstanding -= 1;
+            if resp? == SerialFlasherResponse::Nack {
+                result = SerialFlasherResponse::Nack;
+            }
+        }
+
+        Ok(result)
+    }
+
+    fn write_command(&mut self, cmd: &SerialFlasherCommand) -> Result<(), FlasherError> {
+        // Ping and GetLog can open a session too, not only a Hello.
+        if !self.sent_any && self.flush_before_hello {
+            self.discard_input()?;
+        }
+        self.sent_any = true;
+
+        match cmd {
+            SerialFlasherCommand::WritePage(address, _, checksum) => {
+                note!("Sending WritePage @ {} (CRC8 0x{:02x})", PageAddress::new(*address, self.offset), checksum)
+            }
+            _ => note!("Sending {:?}", cmd),
+        }
+        let ser = postcard::to_stdvec_cobs(&cmd).map_err(|error| FlasherError::CouldntSerialize {
+            command: cmd.name(),
+            field: cmd.failing_field(),
+            error,
+        })?;
+
+        note!("Serialized: {:?}", ser);
+
+        self.dump_frame(cmd.name(), &ser);
+
+        let chunk_size = match (self.frame_aligned, self.chunk_size) {
+            (_, Some(chunk_size)) => chunk_size.get(),
+            (true, None) => ser.len(),
+            (false, None) => DEFAULT_CHUNK_SIZE,
+        };
+
+        self.stats.commands += 1;
+        for chunk in ser.chunks(chunk_size) {
+            note!("Writing chunk");
+            self.port.write_all(chunk).map_err(FlasherError::TransportFailed)?;
+            if !self.frame_aligned {
+                sleep(Duration::from_millis(1));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn dump_frame(&mut self, name: &str, frame: &[u8]) {
+        let Some(dump) = &mut self.dump_sent else {
+            return;
+        };
+
+        let res = dump
+            .write_all(&[name.len() as u8])
+            .and_then(|_| dump.write_all(name.as_bytes()))
+            .and_then(|_| dump.write_all(&(frame.len() as u32).to_le_bytes()))
+            .and_then(|_| dump.write_all(frame));
+
+        if let Err(e) = res {
+            note!("Warning: couldn't write to the sent frame dump, not dumping any more frames: {}", e);
+            self.dump_sent = None;
+        }
+    }
+
+    fn read_response(&mut self, cmd: &SerialFlasherCommand) -> Result<SerialFlasherResponse, FlasherError> {
+        self.read_response_until(cmd, Instant::now() + self.timeout)
+    }
+
+    /// Keeps reading until a complete frame has arrived, since a response is often split over
+    /// several reads. Gives up with `NoResponse` once `deadline` passes without a complete frame.
+    fn read_response_until(&mut self, cmd: &SerialFlasherCommand, deadline: Instant) -> Result<SerialFlasherResponse, FlasherError> {
+        let resp = self.read_frame_until(cmd, deadline);
+        self.tally(&resp);
+        resp
+    }
+
+    /// Counts a read that didn't get a proper answer in the link stats.
+    fn tally(&mut self, resp: &Result<SerialFlasherResponse, FlasherError>) {
+        match resp {
+            Ok(SerialFlasherResponse::Nack) => self.stats.nacks += 1,
+            Err(FlasherError::NoResponse) => self.stats.timeouts += 1,
+            Err(FlasherError::CouldntDeserialize { .. } | FlasherError::UnknownResponse { .. }) => self.stats.decode_errors += 1,
+            _ => {}
+        }
+    }
+
+    fn read_frame_until(&mut self, cmd: &SerialFlasherCommand, deadline: Instant) -> Result<SerialFlasherResponse, FlasherError> {
+        let mut serial_buf = [0u8; 32];
+
+        loop {
+            while let Some(end) = self.received.iter().position(|&b| b == 0) {
+                let mut frame: Vec<u8> = self.received.drain(..=end).collect();
+                if frame.len() == 1 {
+                    continue;
+                }
+
+                let received = frame.clone();
+                return match from_bytes_cobs::<SerialFlasherResponse>(&mut frame) {
+                    Ok(SerialFlasherResponse::Unknown) => {
+                        // The frame now holds the decoded response, which starts with its variant.
+                        let variant = postcard::from_bytes::<u32>(&frame).unwrap_or(u32::MAX);
+                        Err(FlasherError::UnknownResponse { command: cmd.name(), variant })
+                    }
+                    Ok(resp) => {
+                        note!("Received response: {:?}", resp);
+                        Ok(resp)
+                    }
+                    Err(error) => Err(FlasherError::CouldntDeserialize {
+                        command: cmd.name(),
+                        received,
+                        error,
+                    }),
+                };
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(FlasherError::NoResponse);
+            }
+
+            self.port.set_timeout(deadline - now).map_err(FlasherError::TransportFailed)?;
+            match self.port.read(&mut serial_buf) {
+                // The other end closed the connection, waiting longer won't bring an answer.
+                Ok(0) => return Err(FlasherError::TransportFailed(std::io::ErrorKind::UnexpectedEof.into())),
+                Ok(len) => self.received.extend_from_slice(&serial_buf[..len]),
+                // Sockets report their read timeout as WouldBlock.
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock | std::io::ErrorKind::Interrupted
+                    ) => {}
+                Err(e) => return Err(FlasherError::TransportFailed(e)),
+            }
+        }
+    }
+}
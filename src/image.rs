@@ -0,0 +1,625 @@
+use std::collections::BTreeSet;
+use std::ops::Range;
+use std::path::Path;
+use serde_big_array::Array;
+use sha2::{Digest, Sha256};
+use zerocopy::AsBytes;
+use crate::error::FlasherError;
+use crate::protocol::{ChecksumAlgorithm, ImageChecksum, Length, Page, PageAddress, RelativeAddress, SerialFlasherCommand, CRC32, PAGE_SIZE};
+
+/// What unwritten flash reads back as after the erase in PrepareForUpdate.
+pub const ERASED_BYTE: u8 = 0xff;
+
+pub const UF2_BLOCK_SIZE: usize = 512;
+
+/// Family IDs of the boards we know about, for friendlier warnings and output.
+pub const KNOWN_FAMILY_IDS: &[(u32, &str)] = &[
+    (0xe48bff56, "RP2040"),
+    (0xe48bff59, "RP2350 (ARM, secure)"),
+    (0x5ee21072, "STM32F1"),
+    (0x57755a57, "STM32F4"),
+    (0x6db66082, "STM32H7"),
+    (0x00ff6919, "STM32L4"),
+];
+
+/// How the bytes handed to the flasher are laid out.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageFormat {
+    /// UF2 blocks carrying their own absolute addresses.
+    Uf2,
+    /// A raw binary that starts at the flash offset.
+    Bin,
+}
+
+impl std::str::FromStr for ImageFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "uf2" => Ok(ImageFormat::Uf2),
+            "bin" => Ok(ImageFormat::Bin),
+            _ => Err(format!("Unknown image format `{}`, expected uf2 or bin", s)),
+        }
+    }
+}
+
+/// Refuse downloads bigger than this, far beyond any flash we have.
+#[cfg(feature = "http")]
+const MAX_DOWNLOAD_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Reads the firmware from a file, or downloads it if `input` is an http:// or https:// URL.
+pub fn read_input(input: &Path) -> Result<Vec<u8>, FlasherError> {
+    match input.to_str() {
+        Some(url) if url.starts_with("http://") || url.starts_with("https://") => download(url),
+        _ => std::fs::read(input).map_err(FlasherError::CouldntReadInput),
+    }
+}
+
+#[cfg(feature = "http")]
+fn download(url: &str) -> Result<Vec<u8>, FlasherError> {
+    use std::io::Read;
+
+    let failed = |reason: String| FlasherError::DownloadFailed { url: url.to_string(), reason };
+
+    let response = match ureq::get(url).call() {
+        Ok(response) => response,
+        Err(ureq::Error::Status(status, response)) => {
+            return Err(failed(format!("the server answered {} {}", status, response.status_text())));
+        }
+        Err(e) => return Err(failed(e.to_string())),
+    };
+
+    if response.status() != 200 {
+        return Err(failed(format!("the server answered {} {}", response.status(), response.status_text())));
+    }
+
+    let expected = match response.header("Content-Length") {
+        Some(length) => Some(length.parse::<u64>().map_err(|_| failed(format!("invalid Content-Length `{}`", length)))?),
+        None => None,
+    };
+    if let Some(expected) = expected.filter(|&e| e > MAX_DOWNLOAD_SIZE) {
+        return Err(failed(format!("{} bytes is more than the {} byte limit", expected, MAX_DOWNLOAD_SIZE)));
+    }
+
+    let mut body = Vec::new();
+    response
+        .into_reader()
+        .take(MAX_DOWNLOAD_SIZE + 1)
+        .read_to_end(&mut body)
+        .map_err(|e| failed(e.to_string()))?;
+
+    if body.len() as u64 > MAX_DOWNLOAD_SIZE {
+        return Err(failed(format!("the body is more than the {} byte limit", MAX_DOWNLOAD_SIZE)));
+    }
+    if let Some(expected) = expected.filter(|&e| e != body.len() as u64) {
+        return Err(failed(format!("expected {} bytes but received {}", expected, body.len())));
+    }
+
+    note!("Downloaded {} bytes from {}", body.len(), url);
+
+    Ok(body)
+}
+
+/// The bytes of an input, either read into memory or mapped from the file.
+pub enum Input {
+    Read(Vec<u8>),
+    #[cfg(feature = "mmap")]
+    Mapped(memmap2::Mmap),
+}
+
+impl std::ops::Deref for Input {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Input::Read(data) => data,
+            #[cfg(feature = "mmap")]
+            Input::Mapped(map) => map,
+        }
+    }
+}
+
+/// Like [`read_input`], but maps a file into memory instead of reading it, which keeps a large
+/// image out of the heap. URLs are still downloaded.
+#[cfg(feature = "mmap")]
+pub fn map_input(input: &Path) -> Result<Input, FlasherError> {
+    match input.to_str() {
+        Some(url) if url.starts_with("http://") || url.starts_with("https://") => download(url).map(Input::Read),
+        _ => {
+            let file = std::fs::File::open(input).map_err(FlasherError::CouldntReadInput)?;
+            // SAFETY: the mapping is only read, and changing the file while we flash it is as
+            // wrong as it gets anyway. Truncating it could crash us on a SIGBUS, which is the
+            // tradeoff of mapping instead of reading.
+            let map = unsafe { memmap2::Mmap::map(&file) }.map_err(FlasherError::CouldntReadInput)?;
+            Ok(Input::Mapped(map))
+        }
+    }
+}
+
+#[cfg(not(feature = "http"))]
+fn download(url: &str) -> Result<Vec<u8>, FlasherError> {
+    Err(FlasherError::DownloadFailed {
+        url: url.to_string(),
+        reason: "this build doesn't have the `http` feature enabled".to_string(),
+    })
+}
+
+pub fn parse_blocks(image: &[u8]) -> Result<Vec<uftwo::Block>, FlasherError> {
+    if !image.len().is_multiple_of(UF2_BLOCK_SIZE) {
+        return Err(FlasherError::TruncatedUf2 { length: image.len() });
+    }
+
+    image
+        .chunks_exact(UF2_BLOCK_SIZE)
+        .enumerate()
+        .map(|(index, chunk)| uftwo::Block::from_bytes(chunk).map_err(|error| FlasherError::InvalidBlock { index, error }))
+        .collect()
+}
+
+/// Where the payload of a UF2 block starts.
+const UF2_PAYLOAD_START: usize = 32;
+
+/// The target address and payload length fields of a raw UF2 block.
+fn uf2_header(block: &[u8]) -> (u32, u32) {
+    let field = |at: usize| u32::from_le_bytes([block[at], block[at + 1], block[at + 2], block[at + 3]]);
+    (field(12), field(16))
+}
+
+/// How many payload bytes the UF2 blocks in `image` carry for `offset` and above, read straight
+/// from the block headers rather than from parsed blocks, to check the page writes against.
+pub fn uf2_payload_len(image: &[u8], offset: u32) -> usize {
+    image
+        .chunks_exact(UF2_BLOCK_SIZE)
+        .map(uf2_header)
+        .filter(|&(target_addr, _)| target_addr >= offset)
+        .map(|(_, data_len)| data_len as usize)
+        .sum()
+}
+
+/// The absolute address range every UF2 block in `image` covers, from the block headers.
+pub fn uf2_ranges(image: &[u8]) -> impl Iterator<Item = (u64, u64)> + '_ {
+    image.chunks_exact(UF2_BLOCK_SIZE).map(uf2_header).map(|(target_addr, data_len)| {
+        (target_addr as u64, target_addr as u64 + data_len as u64)
+    })
+}
+
+/// Where the bytes of a page come from.
+#[derive(Debug, Clone)]
+enum Source {
+    /// The payload of the UF2 block starting at this byte of the input.
+    Uf2Block(usize),
+    /// Up to a page of the raw binary, from this byte of the input.
+    Bin(usize),
+    /// Nothing but the fill byte.
+    Erased,
+    Stored(Box<Page>),
+}
+
+/// The pages a flash writes, in the order they're sent. Pages of the image only point into the
+/// input and are put together when they're sent or checksummed, so a memory-mapped image never
+/// gets copied into the heap. Only the few pages the flasher makes up itself are stored.
+pub struct Pages<'a> {
+    input: &'a [u8],
+    /// What the device's erased flash reads back as.
+    fill: u8,
+    /// Smallest unit the device programs, for the padding of a short last page of a binary.
+    program_unit: usize,
+    pages: Vec<(RelativeAddress, Source)>,
+}
+
+impl<'a> Pages<'a> {
+    /// One page per UF2 block of `input`. Blocks below `offset` aren't part of the update and are
+    /// skipped.
+    pub fn uf2(input: &'a [u8], offset: u32, fill: u8) -> Result<Self, FlasherError> {
+        if !input.len().is_multiple_of(UF2_BLOCK_SIZE) {
+            return Err(FlasherError::TruncatedUf2 { length: input.len() });
+        }
+
+        let mut pages = Vec::new();
+        let mut lowest = u32::MAX;
+        let mut highest = 0u64;
+        for (index, chunk) in input.chunks_exact(UF2_BLOCK_SIZE).enumerate() {
+            let b = uftwo::Block::from_bytes(chunk).map_err(|error| FlasherError::InvalidBlock { index, error })?;
+            lowest = lowest.min(b.target_addr);
+            highest = highest.max(b.target_addr as u64 + b.data_len as u64);
+
+            if offset > b.target_addr {
+                continue;
+            }
+            if b.data_len as usize != PAGE_SIZE {
+                return Err(FlasherError::UnsupportedBlockLength { target_addr: b.target_addr, data_len: b.data_len });
+            }
+
+            pages.push(((b.target_addr - offset) as RelativeAddress, Source::Uf2Block(index * UF2_BLOCK_SIZE)));
+        }
+
+        if pages.is_empty() && !input.is_empty() {
+            return Err(FlasherError::OffsetAboveImage { offset, lowest, highest });
+        }
+
+        Ok(Pages { input, fill, program_unit: PAGE_SIZE, pages })
+    }
+
+    /// One page per page of a raw binary, starting at relative address 0. A short last page is
+    /// zero-padded up to a multiple of `program_unit` and the rest is left erased (`fill`), so the
+    /// device has no whole program units of padding to write.
+    pub fn bin(input: &'a [u8], program_unit: usize, fill: u8) -> Self {
+        let pages = (0..input.len()).step_by(PAGE_SIZE).map(|start| (start, Source::Bin(start))).collect();
+        Pages { input, fill, program_unit, pages }
+    }
+
+    /// Adds a page holding `page` at `address`.
+    pub fn push(&mut self, address: RelativeAddress, page: Page) {
+        self.pages.push((address, Source::Stored(Box::new(page))));
+    }
+
+    /// Adds an erased page for every page below `pad_to` that isn't written yet and isn't in
+    /// `reserved`, in address order. Returns how many were added.
+    pub fn pad(&mut self, pad_to: usize, reserved: &[RelativeAddress]) -> usize {
+        let written: BTreeSet<RelativeAddress> = self.addresses().chain(reserved.iter().copied()).collect();
+        let before = self.pages.len();
+        for address in (0..pad_to).step_by(PAGE_SIZE).filter(|address| !written.contains(address)) {
+            self.pages.push((address, Source::Erased));
+        }
+        self.pages.len() - before
+    }
+
+    pub fn len(&self) -> usize {
+        self.pages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pages.is_empty()
+    }
+
+    /// The relative address of every page, in the order they're sent.
+    pub fn addresses(&self) -> impl Iterator<Item = RelativeAddress> + '_ {
+        self.pages.iter().map(|&(address, _)| address)
+    }
+
+    /// How far from relative address 0 the pages reach, up to the end of the highest one.
+    pub fn written_len(&self) -> usize {
+        self.addresses().map(|address| address + PAGE_SIZE).max().unwrap_or(0)
+    }
+
+    /// The relative address and contents of the `index`th page.
+    pub fn get(&self, index: usize) -> (RelativeAddress, Page) {
+        let (address, source) = &self.pages[index];
+        let page = match source {
+            Source::Uf2Block(start) => {
+                let payload = start + UF2_PAYLOAD_START;
+                let mut page = Array([0u8; PAGE_SIZE]);
+                page.copy_from_slice(&self.input[payload..payload + PAGE_SIZE]);
+                page
+            }
+            Source::Bin(start) => {
+                let chunk = &self.input[*start..(start + PAGE_SIZE).min(self.input.len())];
+                let mut page = Array([self.fill; PAGE_SIZE]);
+                page[..chunk.len().next_multiple_of(self.program_unit)].fill(0);
+                page[..chunk.len()].copy_from_slice(chunk);
+                page
+            }
+            Source::Erased => Array([self.fill; PAGE_SIZE]),
+            Source::Stored(page) => **page,
+        };
+        (*address, page)
+    }
+}
+
+/// Byte order of the multi-byte metadata fields the flasher stamps into pages. The firmware
+/// payload is always written exactly as it is in the input.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Endian {
+    Little,
+    Big,
+}
+
+impl Endian {
+    pub fn u32_bytes(self, value: u32) -> [u8; 4] {
+        match self {
+            Endian::Little => value.to_le_bytes(),
+            Endian::Big => value.to_be_bytes(),
+        }
+    }
+}
+
+impl std::str::FromStr for Endian {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "little" => Ok(Endian::Little),
+            "big" => Ok(Endian::Big),
+            _ => Err(format!("Unknown byte order `{}`, expected little or big", s)),
+        }
+    }
+}
+
+/// Parses a number in decimal or 0x-prefixed hex, the way addresses and lengths are given on the
+/// command line.
+pub fn parse_number(s: &str) -> Result<u32, std::num::ParseIntError> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+}
+
+/// A window of the image, in relative addresses, that the final checksum is limited to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChecksumRegion {
+    pub start: RelativeAddress,
+    pub length: Length,
+}
+
+impl ChecksumRegion {
+    pub fn end(&self) -> RelativeAddress {
+        self.start + self.length as RelativeAddress
+    }
+}
+
+impl std::fmt::Display for ChecksumRegion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "0x{:x}:0x{:x}", self.start, self.length)
+    }
+}
+
+impl std::str::FromStr for ChecksumRegion {
+    type Err = String;
+
+    /// Parses `start:length`, both in decimal or 0x-prefixed hex.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, length) = s
+            .split_once(':')
+            .ok_or_else(|| format!("Expected START:LENGTH, got `{}`", s))?;
+        let start = parse_number(start).map_err(|e| format!("Invalid region start `{}`: {}", start, e))?;
+        let length = parse_number(length).map_err(|e| format!("Invalid region length `{}`: {}", length, e))?;
+        if length == 0 {
+            return Err(format!("The region `{}` is empty", s));
+        }
+
+        Ok(ChecksumRegion { start: start as RelativeAddress, length })
+    }
+}
+
+/// The checks that end a flash of `pages`: one CompareChecksum over the whole image, or one
+/// CompareRegionChecksum per region if there are any. Gaps are checksummed as erased.
+pub fn checksum_commands(pages: &Pages, regions: &[ChecksumRegion], algorithm: ChecksumAlgorithm) -> Vec<SerialFlasherCommand> {
+    if regions.is_empty() {
+        let len = pages.written_len();
+        return vec![SerialFlasherCommand::CompareChecksum(len as Length, flashed_checksum(pages, 0..len, algorithm))];
+    }
+
+    regions
+        .iter()
+        .map(|region| {
+            let checksum = flashed_checksum(pages, region.start..region.end(), algorithm);
+            SerialFlasherCommand::CompareRegionChecksum(region.start, region.length, checksum)
+        })
+        .collect()
+}
+
+/// Text stamped into a page of its own, like a build identifier the device can report later.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trailer {
+    pub text: String,
+    pub address: RelativeAddress,
+}
+
+impl std::str::FromStr for Trailer {
+    type Err = String;
+
+    /// Parses `text@address`, with the relative address in decimal or 0x-prefixed hex.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (text, address) = s
+            .rsplit_once('@')
+            .ok_or_else(|| format!("Expected TEXT@ADDRESS, got `{}`", s))?;
+        let address = parse_number(address).map_err(|e| format!("Invalid trailer address `{}`: {}", address, e))?;
+
+        Ok(Trailer { text: text.to_string(), address: address as RelativeAddress })
+    }
+}
+
+/// The page for a trailer: its UTF-8 bytes, zero-padded. It goes at `trailer.address`.
+pub fn trailer_page(trailer: &Trailer, offset: u32) -> Result<Page, FlasherError> {
+    let bytes = trailer.text.as_bytes();
+    let address = PageAddress::new(trailer.address, offset);
+
+    if bytes.len() > PAGE_SIZE {
+        return Err(FlasherError::TrailerTooLong { length: bytes.len() });
+    }
+    if !trailer.address.is_multiple_of(PAGE_SIZE) {
+        return Err(FlasherError::UnalignedTrailer { address });
+    }
+    if address.absolute() + PAGE_SIZE as u64 > 1 << 32 {
+        return Err(FlasherError::TrailerOutOfRange { address });
+    }
+
+    let mut page = Array([0u8; PAGE_SIZE]);
+    page[..bytes.len()].copy_from_slice(bytes);
+    Ok(page)
+}
+
+/// Makes sure every page lands on a page boundary and that no two pages cover the same flash.
+/// `offset` is only used to show absolute addresses in errors.
+pub fn check_write_addresses(addresses: impl IntoIterator<Item = RelativeAddress>, offset: u32) -> Result<(), FlasherError> {
+    let mut addresses: Vec<RelativeAddress> = addresses.into_iter().collect();
+
+    if let Some(&address) = addresses.iter().find(|&&a| a % PAGE_SIZE != 0) {
+        return Err(FlasherError::UnalignedWrite { address: PageAddress::new(address, offset) });
+    }
+
+    addresses.sort();
+    if let Some(pair) = addresses.windows(2).find(|pair| pair[1] < pair[0] + PAGE_SIZE) {
+        return Err(FlasherError::OverlappingWrites {
+            first: PageAddress::new(pair[0], offset),
+            second: PageAddress::new(pair[1], offset),
+        });
+    }
+
+    Ok(())
+}
+
+/// Makes sure the absolute `ranges` of the blocks, like [`uf2_ranges`], hold data for every byte
+/// of `[offset, offset + length)`, so a wrong `length` is caught before anything is sent.
+pub fn check_coverage(ranges: impl IntoIterator<Item = (u64, u64)>, offset: u32, length: u32) -> Result<(), FlasherError> {
+    let end = offset as u64 + length as u64;
+    let mut ranges: Vec<(u64, u64)> = ranges.into_iter().collect();
+    ranges.sort();
+
+    let mut covered = offset as u64;
+    for (start, stop) in ranges {
+        if covered >= end || start > covered {
+            break;
+        }
+        covered = covered.max(stop);
+    }
+
+    if covered >= end {
+        Ok(())
+    } else {
+        Err(FlasherError::WindowNotCovered { offset, end, missing: covered })
+    }
+}
+
+/// First field of a footer page, "VFTR" when written big endian.
+pub const FOOTER_MAGIC: u32 = 0x5646_5452;
+
+/// The footer page for bootloaders that check the image at every boot, placed at relative
+/// address `address` after the rest of the image. All integers are in `endian`:
+///
+/// | offset | size | field                                                        |
+/// |--------|------|--------------------------------------------------------------|
+/// | 0      | 4    | [`FOOTER_MAGIC`]                                             |
+/// | 4      | 4    | image length in bytes, counted from relative address 0       |
+/// | 8      | 4    | checksum algorithm: 0 for SHA-256, 1 for CRC32               |
+/// | 12     | 32   | the SHA-256 digest as is, or the CRC32 followed by 28 zeros  |
+///
+/// The rest of the page is zero. The length is that of all of `pages`, which come before the
+/// footer, and the checksum covers it with the gaps left erased.
+pub fn footer_page(
+    pages: &Pages,
+    address: RelativeAddress,
+    offset: u32,
+    algorithm: ChecksumAlgorithm,
+    endian: Endian,
+) -> Result<Page, FlasherError> {
+    let page_address = PageAddress::new(address, offset);
+    let image_len = pages.written_len();
+    if !address.is_multiple_of(PAGE_SIZE) {
+        return Err(FlasherError::UnalignedFooter { address: page_address });
+    }
+    if address < image_len {
+        return Err(FlasherError::FooterInsideImage { address: page_address, image_len });
+    }
+    if page_address.absolute() + PAGE_SIZE as u64 > 1 << 32 {
+        return Err(FlasherError::FooterOutOfRange { address: page_address });
+    }
+
+    let mut page = Array([0u8; PAGE_SIZE]);
+    page[0..4].copy_from_slice(&endian.u32_bytes(FOOTER_MAGIC));
+    page[4..8].copy_from_slice(&endian.u32_bytes(image_len as u32));
+    match flashed_checksum(pages, 0..image_len, algorithm) {
+        ImageChecksum::Sha256(digest) => page[12..44].copy_from_slice(&digest),
+        ImageChecksum::Crc32(crc) => {
+            page[8..12].copy_from_slice(&endian.u32_bytes(1));
+            page[12..16].copy_from_slice(&endian.u32_bytes(crc));
+        }
+    }
+
+    Ok(page)
+}
+
+/// Hands the bytes in `range` of the update slot, as it reads once all `pages` are written, to
+/// `f` in order. Gaps between pages stay erased, reading back as the fill byte. Neither the slot
+/// nor the pages are built in memory beyond one page at a time, so this costs nothing however
+/// large the image. Where pages overlap, which only `--force` lets through, the higher one wins,
+/// and the last one sent if they start at the same address.
+pub fn for_each_flashed(pages: &Pages, range: Range<usize>, mut f: impl FnMut(&[u8])) {
+    let mut order: Vec<(RelativeAddress, usize)> = pages.addresses().enumerate().map(|(i, address)| (address, i)).collect();
+    order.sort();
+
+    let erased = [pages.fill; PAGE_SIZE];
+    fn erased_up_to(f: &mut impl FnMut(&[u8]), erased: &[u8], pos: &mut usize, to: usize) {
+        while *pos < to {
+            let n = (to - *pos).min(erased.len());
+            f(&erased[..n]);
+            *pos += n;
+        }
+    }
+
+    let mut pos = range.start;
+    for (i, &(address, index)) in order.iter().enumerate() {
+        if pos >= range.end {
+            return;
+        }
+
+        erased_up_to(&mut f, &erased, &mut pos, address.min(range.end));
+        let page_end = match order.get(i + 1) {
+            Some(&(next, _)) => next.min(address + PAGE_SIZE),
+            None => address + PAGE_SIZE,
+        };
+        let to = page_end.min(range.end);
+        if pos < to {
+            let (_, page) = pages.get(index);
+            f(&page[pos - address..to - address]);
+            pos = to;
+        }
+    }
+    erased_up_to(&mut f, &erased, &mut pos, range.end);
+}
+
+/// The checksum of `range` of the update slot once `pages` are written, see
+/// [`for_each_flashed`].
+pub fn flashed_checksum(pages: &Pages, range: Range<usize>, algorithm: ChecksumAlgorithm) -> ImageChecksum {
+    match algorithm {
+        ChecksumAlgorithm::Sha256 => {
+            let mut hasher = Sha256::new();
+            for_each_flashed(pages, range, |bytes| hasher.update(bytes));
+            ImageChecksum::Sha256(hasher.finalize().into())
+        }
+        ChecksumAlgorithm::Crc32 => {
+            let mut digest = CRC32.digest();
+            for_each_flashed(pages, range, |bytes| digest.update(bytes));
+            ImageChecksum::Crc32(digest.finalize())
+        }
+    }
+}
+
+pub fn image_checksum(image: &[u8], algorithm: ChecksumAlgorithm) -> ImageChecksum {
+    match algorithm {
+        ChecksumAlgorithm::Sha256 => ImageChecksum::Sha256(Sha256::digest(image).into()),
+        ChecksumAlgorithm::Crc32 => ImageChecksum::Crc32(CRC32.checksum(image)),
+    }
+}
+
+/// Packs a raw binary into a UF2 file with one page per block, starting at the absolute address
+/// `offset`. The last page is padded with zeros.
+pub fn pack_uf2(data: &[u8], offset: u32, family_id: u32) -> Result<Vec<u8>, FlasherError> {
+    if !(offset as usize).is_multiple_of(PAGE_SIZE) {
+        return Err(FlasherError::UnalignedOffset { offset });
+    }
+
+    if family_id == 0 {
+        return Err(FlasherError::InvalidFamilyId(family_id));
+    }
+
+    let total_blocks = data.len().div_ceil(PAGE_SIZE);
+    if offset as u64 + (total_blocks * PAGE_SIZE) as u64 > u32::MAX as u64 + 1 {
+        return Err(FlasherError::ImageOutOfRange { offset, length: data.len() });
+    }
+
+    let mut uf2 = Vec::with_capacity(total_blocks * UF2_BLOCK_SIZE);
+    for (index, chunk) in data.chunks(PAGE_SIZE).enumerate() {
+        let mut page = [0u8; PAGE_SIZE];
+        page[..chunk.len()].copy_from_slice(chunk);
+
+        let mut block = uftwo::Block::new(index, total_blocks, &page, offset as usize + index * PAGE_SIZE);
+        block.data_len = PAGE_SIZE as u32;
+        block.flags = uftwo::Flags::FamilyId;
+        block.board_family_id_or_file_size = family_id;
+
+        uf2.extend_from_slice(block.as_bytes());
+    }
+
+    Ok(uf2)
+}
@@ -0,0 +1,16 @@
+#[macro_use]
+pub mod log;
+pub mod board;
+mod error;
+mod flasher;
+pub mod image;
+pub mod protocol;
+#[cfg(unix)]
+pub mod pty;
+pub mod sim;
+pub mod timings;
+pub mod transport;
+
+pub use error::{FlasherError, PageMismatch, TransportOpenError};
+pub use flasher::{FlashOptions, Flasher, LinkStats, Phase, Plan, Progress, VerifyMode};
+pub use protocol::{RelativeAddress, PAGE_SIZE};
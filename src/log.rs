@@ -0,0 +1,36 @@
+//! Where diagnostics go. Everything is written to stderr, and also to a copy while one is set, so
+//! a batch can keep each device's messages in a file of its own.
+
+use std::fmt;
+use std::fs::File;
+use std::io::Write;
+use std::sync::Mutex;
+
+static COPY: Mutex<Option<File>> = Mutex::new(None);
+
+/// Starts copying diagnostics to `file`, or stops with `None`. Returns the file copied to before.
+pub fn copy_to(file: Option<File>) -> Option<File> {
+    let mut copy = COPY.lock().unwrap_or_else(|e| e.into_inner());
+    std::mem::replace(&mut *copy, file)
+}
+
+/// Writes one line to stderr and to the copy. A copy that can't be written to is dropped with a
+/// warning rather than failing whatever was being reported.
+pub fn line(args: fmt::Arguments) {
+    eprintln!("{}", args);
+    let mut copy = COPY.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(file) = copy.as_mut() {
+        if let Err(e) = writeln!(file, "{}", args) {
+            *copy = None;
+            eprintln!("Warning: couldn't write to the log file, not copying any more messages: {}", e);
+        }
+    }
+}
+
+/// Like `eprintln!`, but also writes to the copy set with [`copy_to`].
+#[macro_export]
+macro_rules! note {
+    ($($arg:tt)*) => {
+        $crate::log::line(format_args!($($arg)*))
+    };
+}
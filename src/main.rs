@@ -1,34 +1,51 @@
-use std::io::Read;
-use std::cmp::PartialEq;
 use std::fs::File;
+use std::io::{IsTerminal, Write};
+use std::mem::ManuallyDrop;
 use std::net::SocketAddr;
-use std::path::PathBuf;
-use std::thread::sleep;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
-use postcard::accumulator::CobsAccumulator;
-use postcard::from_bytes_cobs;
-use postcard::ser_flavors::Cobs;
-use serde::{Deserialize, Serialize};
-use serde_big_array::Array;
-use serialport::SerialPort;
+use serde::Deserialize;
+use structopt::clap::{AppSettings, ErrorKind};
 use structopt::StructOpt;
-use crate::SerialFlasherCommand::WritePage;
+use variegated_serial_updater::board::{find_board, BoardProfile};
+use variegated_serial_updater::protocol::{ChecksumAlgorithm, ImageChecksum, SerialFlasherCommand};
+use variegated_serial_updater::image::{self, parse_number, ChecksumRegion, Endian, ImageFormat, Trailer};
+#[cfg(feature = "tls")]
+use variegated_serial_updater::transport::TlsOptions;
+use variegated_serial_updater::{log, note, FlashOptions, Flasher, FlasherError, Phase, TransportOpenError, RelativeAddress, VerifyMode, PAGE_SIZE};
 
-type RelativeAddress = usize;
-type Length = u32;
-type Page = Array<u8, 256>;
-type Crc8Checksum = u8;
-type Sha256Checksum = [u8; 16];
 
-#[derive(Debug, StructOpt)]
-#[structopt(name = "my_program", about = "A CLI application example")]
+#[derive(Debug, Clone, StructOpt)]
+#[structopt(
+    name = "my_program",
+    about = "A CLI application example",
+    after_help = "Diagnostics and progress are written to stderr. Stdout only carries program output, like the list-blocks table and ping results.\n\nExits with 1 on errors, with 2 if the device never answered the initial Hello, and with 3 (no such port), 4 (permission denied), 5 (port busy) or 6 (bad address) if the port couldn't be opened.\n\nWith --exit-bits, a failed flash instead exits with one bit set for the phase it failed in: 1 handshake, 2 erase, 4 write (including FinishedWriting), 8 verify, 16 mark updated, 32 before reaching the device (arguments, input file, opening the port).",
+    setting = AppSettings::SubcommandsNegateReqs
+)]
 struct Opt {
-    /// Input file
+    #[structopt(subcommand)]
+    command: Option<Command>,
+
+    /// Input file, or an http(s):// URL when built with the `http` feature
     #[structopt(parse(from_os_str))]
-    input: PathBuf,
+    input: Option<PathBuf>,
+
+    /// Absolute address of the application slot, in decimal or 0x-prefixed hex. Defaults to the
+    /// board's offset when --board is given
+    #[structopt(long, parse(try_from_str = parse_number))]
+    offset: Option<u32>,
 
-    #[structopt(long, default_value = "268451840")]
-    offset: u32,
+    /// Board profile supplying defaults for --offset, --baud and --image-checksum (rp2040, stm32f4, stm32h7)
+    #[structopt(long, parse(try_from_str = find_board))]
+    board: Option<&'static BoardProfile>,
+
+    /// Serial baud rate. Defaults to the board's baud rate when --board is given, 9600 otherwise
+    #[structopt(long)]
+    baud: Option<u32>,
 
     /// Serial port path
     #[structopt(long, conflicts_with = "tcp", required_unless = "tcp")]
@@ -37,121 +54,972 @@ struct Opt {
     /// TCP address in the format IP:PORT
     #[structopt(long, conflicts_with = "port", required_unless = "port")]
     tcp: Option<SocketAddr>,
+
+    /// Wrap the --tcp connection in TLS
+    #[cfg(feature = "tls")]
+    #[structopt(long, requires = "tcp")]
+    tls: bool,
+
+    /// PEM file with the CA certificates to trust for --tls. Defaults to the bundled web roots
+    #[cfg(feature = "tls")]
+    #[structopt(long, parse(from_os_str), requires = "tls")]
+    ca_cert: Option<PathBuf>,
+
+    /// Name the --tls server's certificate must be for. Defaults to the --tcp IP address
+    #[cfg(feature = "tls")]
+    #[structopt(long, requires = "tls")]
+    server_name: Option<String>,
+
+    /// Format of the input: uf2, or bin for a raw binary that starts at --offset
+    #[structopt(long, default_value = "uf2")]
+    format: ImageFormat,
+
+    /// Checksum over the whole image for CompareChecksum: sha256 or crc32. Defaults to the board's
+    /// algorithm when --board is given, sha256 otherwise
+    #[structopt(long)]
+    image_checksum: Option<ChecksumAlgorithm>,
+
+    /// How to check the flash after writing: checksum, or full to also read back every page and
+    /// list all the ranges that differ
+    #[structopt(long, default_value = "checksum")]
+    verify: VerifyMode,
+
+    /// Limit the final checksum to START:LENGTH (relative, decimal or 0x-prefixed hex), for
+    /// images with areas the device changes itself. Can be given several times
+    #[structopt(long = "checksum-region", number_of_values = 1)]
+    checksum_regions: Vec<ChecksumRegion>,
+
+    /// What the device's erased flash reads back as, used for padding and the gaps between pages
+    /// in the checksum. In decimal or 0x-prefixed hex
+    #[structopt(long, default_value = "0xff", parse(try_from_str = parse_byte))]
+    fill_byte: u8,
+
+    /// Fill the slot with erased pages up to this many bytes from --offset, covering any gaps in
+    /// the image too. A multiple of 256, in decimal or 0x-prefixed hex
+    #[structopt(long, parse(try_from_str = parse_number))]
+    pad_image_to: Option<u32>,
+
+    /// Smallest number of bytes the device programs at once. A short last page of a bin input is
+    /// padded to a multiple of this instead of a whole page. Must divide the 256 byte page
+    #[structopt(long, parse(try_from_str = parse_number))]
+    program_unit: Option<u32>,
+
+    /// Number of bytes from --offset the input must cover. Checked before talking to the device
+    #[structopt(long, parse(try_from_str = parse_number))]
+    length: Option<u32>,
+
+    /// Also write TEXT, zero-padded, into its own page at the relative ADDRESS, e.g. a build
+    /// identifier like `--trailer "v1.2.0 3f2a9c1"@0xff00`. Can be given more than once
+    #[structopt(long = "trailer", value_name = "TEXT@ADDRESS", number_of_values = 1)]
+    trailers: Vec<Trailer>,
+
+    /// Write a footer page at this relative address, after the rest of the image, for
+    /// bootloaders that check the image at boot. In --endian: the magic 0x56465452 ("VFTR" big
+    /// endian), the length of everything before the footer, the checksum algorithm (0 sha256,
+    /// 1 crc32), then the --image-checksum over those bytes (32 bytes; a CRC32 is zero-padded).
+    /// The rest of the page is zero
+    #[structopt(long, value_name = "ADDRESS", parse(try_from_str = parse_number))]
+    footer: Option<u32>,
+
+    /// Byte order for multi-byte metadata the flasher stamps into pages: little or big. The image
+    /// itself is never byte swapped
+    #[structopt(long, default_value = "little")]
+    endian: Endian,
+
+    /// Send a Hello every N milliseconds while waiting for the erase in PrepareForUpdate to finish
+    #[structopt(long)]
+    keepalive_ms: Option<u64>,
+
+    /// Turn the host-side sanity checks into warnings instead of refusing to flash. This covers
+    /// unaligned page writes, overlapping page writes and an image with nothing to write, which
+    /// only erases the slot. It never ignores transport errors or NACKs.
+    #[structopt(long)]
+    force: bool,
+
+    /// Send the initial Hello up to N times, waiting longer each time, before giving up on the device
+    #[structopt(long, default_value = "1")]
+    hello_attempts: u32,
+
+    /// Resend a page up to N times when the device NACKs it
+    #[structopt(long, default_value = "0")]
+    retry_on_nack: u32,
+
+    /// Resend a page up to N times when the device doesn't answer
+    #[structopt(long, default_value = "0")]
+    retry_on_timeout: u32,
+
+    /// Resend a page up to N times when the device's answer doesn't decode, e.g. after a glitch on the line
+    #[structopt(long, default_value = "0")]
+    retry_on_decode: u32,
+
+    /// Keep writing after a page runs out of retries and list all failed pages at the end
+    #[structopt(long)]
+    keep_going: bool,
+
+    /// Warn at the end of a flash when more than this percentage of commands got a NACK, no
+    /// answer or a garbled one, even if retries saved the flash
+    #[structopt(long, default_value = "5")]
+    error_rate_warn: f64,
+
+    /// Don't ask for confirmation before flashing, even on a terminal
+    #[structopt(long, short = "y")]
+    yes: bool,
+
+    /// If the first page is NACKed right after the erase, assume the erase reset the board and
+    /// handshake again, resuming without another erase
+    #[structopt(long)]
+    recover_reset: bool,
+
+    /// Give up once this many page retries have been spent across the whole flash, however many
+    /// each page still has left
+    #[structopt(long)]
+    max_total_retries: Option<u32>,
+
+    /// Don't have the device check each page's CRC8, for fast transfers over links known to be
+    /// reliable. Corrupted pages are then only caught by the final checksum compare. The device
+    /// must support this, it's negotiated in the Hello
+    #[structopt(long)]
+    no_page_crc: bool,
+
+    /// Write progress events to this file descriptor as newline delimited JSON, like
+    /// {"phase":"write","done":42,"total":184}. Unix only
+    #[structopt(long)]
+    progress_fd: Option<i32>,
+
+    /// Print the round trip times of every command, the erase time and a histogram of page write
+    /// latencies to stderr at the end
+    #[structopt(long)]
+    timings: bool,
+
+    /// Encode the phase a failed flash stopped in as bit flags in the exit code, see below
+    #[structopt(long)]
+    exit_bits: bool,
+
+    /// Memory-map the input file instead of reading it into memory, for very large images. The
+    /// file must not change while flashing
+    #[cfg(feature = "mmap")]
+    #[structopt(long)]
+    mmap: bool,
+
+    /// Largest number of bytes written to the port at once. Defaults to 16, or to the whole frame
+    /// with --frame-aligned
+    #[structopt(long)]
+    chunk_size: Option<NonZeroUsize>,
+
+    /// Write each command frame in a single write instead of 16 byte chunks with pauses, for
+    /// devices that expect frame-aligned reads
+    #[structopt(long)]
+    frame_aligned: bool,
+
+    /// Take a plain Ack to the checksum compare as a pass, for devices whose firmware predates
+    /// naming the compared algorithm in the answer
+    #[structopt(long)]
+    accept_bare_checksum_ack: bool,
+
+    /// Don't throw away bytes already waiting on the port before the first command and the
+    /// handshake. They are usually left over from an aborted session and would be read as the
+    /// answer to the Hello, Ping or GetLog
+    #[structopt(long)]
+    no_flush: bool,
+
+    /// Append every frame sent to the device to this file, preceded by the command name and length
+    #[structopt(long, parse(from_os_str))]
+    dump_sent: Option<PathBuf>,
 }
 
+#[derive(Debug, Clone, StructOpt)]
+enum Command {
+    /// Print the checksum a flash of a file would have the device compare, without flashing it
+    ///
+    /// Honours --offset, --format, --length, --fill-byte, --image-checksum and
+    /// --checksum-region, and prints one line per compare: its relative start, length and value.
+    Checksum {
+        /// Input file
+        #[structopt(parse(from_os_str))]
+        input: PathBuf,
+    },
+    /// Print the blocks of a UF2 file without flashing it
+    ListBlocks {
+        /// Input file
+        #[structopt(parse(from_os_str))]
+        input: PathBuf,
+    },
+    /// Pack a raw binary into a UF2 file, placing it at --offset
+    Pack {
+        /// Raw binary input file
+        #[structopt(parse(from_os_str))]
+        input: PathBuf,
+
+        /// UF2 output file
+        #[structopt(parse(from_os_str))]
+        output: PathBuf,
 
-const CRC8: crc::Crc<u8> = crc::Crc::<u8>::new(&crc::CRC_8_SMBUS);
+        /// UF2 family ID, in decimal or 0x-prefixed hex
+        #[structopt(long, parse(try_from_str = parse_number))]
+        family_id: u32,
+    },
+    /// Check that the device answers the handshake, without erasing or writing anything
+    ///
+    /// Sends only the Hello a flash would open with and exits with 0 if the device ACKs it. The
+    /// protocol has no device info command yet, so there is nothing more to report.
+    Check,
+    /// Send pings to the device and report the round trip times
+    Ping {
+        /// Number of pings to send
+        #[structopt(long, default_value = "10")]
+        count: u32,
+    },
+    /// Fetch and print the device's diagnostic log
+    Log,
+    /// Find the device's baud rate by sending Hello at each of a list of rates
+    ///
+    /// Stops at the first rate that gets an answer and prints it, for use with --baud.
+    Probe {
+        /// Baud rates to try, in order
+        #[structopt(long, use_delimiter = true, default_value = "9600,57600,115200,230400,460800")]
+        rates: Vec<u32>,
 
-#[derive(Serialize, Debug)]
-enum SerialFlasherCommand {
-    Hello,
-    PrepareForUpdate,
-    WritePage(RelativeAddress, Page, Crc8Checksum),
-    FinishedWriting,
-    CompareChecksum(Length, Sha256Checksum),
-    MarkUpdated
+        /// How long to wait for an answer at each rate
+        #[structopt(long, default_value = "500")]
+        timeout_ms: u64,
+    },
+    /// Flash every device listed in a TOML manifest, continuing past failures
+    ///
+    /// Each [[device]] needs `image` and one of `port` or `tcp`, and may set `name`, `baud`,
+    /// `offset` and `trailers` (TEXT@ADDRESS, like --trailer). Image paths are relative to the
+    /// manifest. All other options apply to every device. Prints a pass/fail table with each
+    /// device's exit status, and exits with 1 if any device failed.
+    Batch {
+        /// Manifest file
+        #[structopt(parse(from_os_str))]
+        manifest: PathBuf,
+
+        /// Also write each device's messages, with timings, to a file of its own in this
+        /// directory, named after the device's name, or its port or address when it has none
+        #[structopt(long, parse(from_os_str))]
+        output_dir: Option<PathBuf>,
+    },
+    /// Act as a device on a new pseudo-terminal, for testing without hardware (Unix only)
+    ///
+    /// Prints the path to pass to --port, then answers commands until the device is marked updated.
+    Simulate {
+        /// Start from the flash contents in this file, and write the final contents back to it
+        /// after MarkUpdated. A missing file starts out erased
+        #[structopt(long, parse(from_os_str))]
+        flash_image: Option<PathBuf>,
+
+        /// What erased flash reads back as, in decimal or 0x-prefixed hex
+        #[structopt(long, default_value = "0xff", parse(try_from_str = parse_byte))]
+        fill_byte: u8,
+
+        /// Reset right after the first erase, like a board that browns out, to try --recover-reset
+        #[structopt(long)]
+        reset_after_prepare: bool,
+    },
 }
 
-#[derive(Deserialize, Debug, PartialEq)]
-enum SerialFlasherResponse {
-    Ack,
-    Nack,
+fn parse_byte(s: &str) -> Result<u8, String> {
+    let n = parse_number(s).map_err(|e| e.to_string())?;
+    u8::try_from(n).map_err(|_| format!("{} doesn't fit in a byte", s))
 }
 
-#[derive(Debug)]
-enum FlasherError {
-    NoResponse,
-    CouldntDeserialize,
+impl Opt {
+    fn offset(&self) -> Option<u32> {
+        self.offset.or(self.board.map(|b| b.offset))
+    }
+
+    fn required_offset(&self) -> u32 {
+        self.offset().unwrap_or_else(|| {
+            structopt::clap::Error::with_description(
+                "Either --offset or --board needs to be provided",
+                ErrorKind::MissingRequiredArgument,
+            )
+            .exit()
+        })
+    }
+
+    fn baud_rate(&self) -> u32 {
+        self.baud.or(self.board.map(|b| b.baud_rate)).unwrap_or(9600)
+    }
+
+    fn image_checksum(&self) -> ChecksumAlgorithm {
+        self.image_checksum.or(self.board.map(|b| b.image_checksum)).unwrap_or(ChecksumAlgorithm::Sha256)
+    }
+
+    fn flash_options(&self) -> FlashOptions {
+        FlashOptions {
+            format: self.format,
+            image_checksum: self.image_checksum(),
+            checksum_regions: self.checksum_regions.clone(),
+            verify: self.verify,
+            fill_byte: self.fill_byte,
+            pad_to: self.pad_image_to,
+            program_unit: self.program_unit.map_or(PAGE_SIZE, |unit| unit as usize),
+            length: self.length,
+            trailers: self.trailers.clone(),
+            footer: self.footer.map(|address| address as RelativeAddress),
+            endian: self.endian,
+            keepalive: self.keepalive_ms.map(Duration::from_millis),
+            force: self.force,
+            ..FlashOptions::new(self.required_offset())
+        }
+    }
 }
 
 fn main() {
     let opt = Opt::from_args();
+    let exit_bits = opt.exit_bits && opt.command.is_none();
 
-    println!("Input file: {:?}", opt.input);
+    let res = match &opt.command {
+        Some(Command::Checksum { input }) => checksum(&opt, input),
+        Some(Command::ListBlocks { input }) => list_blocks(input, opt.offset()),
+        Some(Command::Pack { input, output, family_id }) => pack(input, output, opt.required_offset(), *family_id),
+        Some(Command::Check) => check(&opt),
+        Some(Command::Ping { count }) => ping(&opt, *count),
+        Some(Command::Log) => print_log(&opt),
+        Some(Command::Probe { rates, timeout_ms }) => probe(&opt, rates, Duration::from_millis(*timeout_ms)),
+        Some(Command::Batch { manifest, output_dir }) => batch(&opt, manifest, output_dir.as_deref()),
+        Some(Command::Simulate { flash_image, fill_byte, reset_after_prepare }) => {
+            simulate(flash_image.as_deref(), *fill_byte, *reset_after_prepare)
+        }
+        None => run(opt),
+    };
 
-    let mut file = File::open(opt.input).expect("File needs to be able to open");
-    let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer).expect("Have to be able to read the file");
+    if let Err(e) = res {
+        note!("{}", e);
+        if exit_bits {
+            exit(phase_exit_bits(None));
+        }
+        exit(exit_code(&e));
+    }
+}
 
-    let blocks = buffer.chunks_exact(512);
-    let write_commands = blocks
-        .map(|chunk| uftwo::Block::from_bytes(chunk).expect("Gotta be able to parse chunk"))
-        .map(|b| {
-            if opt.offset > b.target_addr {
-                return None;
+/// The exit code for a failure, after printing a hint if there is one.
+fn exit_code(e: &FlasherError) -> i32 {
+    match e {
+        FlasherError::DeviceUnreachable { .. } => 2,
+        FlasherError::CouldntOpenTransport(e) => match e {
+            TransportOpenError::NoSuchPort(_) => 3,
+            TransportOpenError::PermissionDenied(_) => {
+                note!("On Linux, check that your user is in the dialout (or uucp) group");
+                4
+            }
+            TransportOpenError::PortBusy(_) => {
+                note!("Close any serial monitor that has the port open and try again");
+                5
             }
+            TransportOpenError::BadAddress(_) => 6,
+            TransportOpenError::Other { .. } | TransportOpenError::Unreachable { .. } | TransportOpenError::Tls { .. } => 1,
+        },
+        _ => 1,
+    }
+}
 
-            let relative_address =  b.target_addr - opt.offset;
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Manifest {
+    #[serde(rename = "device")]
+    devices: Vec<ManifestDevice>,
+}
 
-            if b.data_len != 256 {
-                panic!("Non-256 length block");
-            }
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ManifestDevice {
+    name: Option<String>,
+    port: Option<PathBuf>,
+    tcp: Option<SocketAddr>,
+    baud: Option<u32>,
+    offset: Option<u32>,
+    image: PathBuf,
+    #[serde(default)]
+    trailers: Vec<String>,
+}
 
-            let mut data = Array::<u8, 256>::default();
-            data.copy_from_slice(&b.data[..256]);
+impl ManifestDevice {
+    /// The options for flashing this device: the command line's, with the device's on top.
+    fn options(&self, opt: &Opt, base: &Path) -> Result<Opt, String> {
+        if self.port.is_some() == self.tcp.is_some() {
+            return Err("needs exactly one of port or tcp".to_string());
+        }
 
-            let checksum = CRC8.checksum(&b.data[..256]);
+        // Without one, flashing would exit the whole batch the way a missing --offset does.
+        if self.offset.is_none() && opt.offset().is_none() {
+            return Err("needs an offset, from the manifest, --offset or --board".to_string());
+        }
 
-            let page_write = WritePage(relative_address as usize, data, checksum);
+        let mut trailers = opt.trailers.clone();
+        for trailer in &self.trailers {
+            trailers.push(trailer.parse()?);
+        }
 
-            Some(page_write)
+        Ok(Opt {
+            command: None,
+            input: Some(base.join(&self.image)),
+            port: self.port.clone(),
+            tcp: self.tcp,
+            baud: self.baud.or(opt.baud),
+            offset: self.offset.or(opt.offset),
+            trailers,
+            // The batch asks once up front, and one failed device mustn't end the batch.
+            yes: true,
+            exit_bits: false,
+            ..opt.clone()
         })
-        .flatten();
+    }
+
+    fn target(&self) -> String {
+        match (&self.port, self.tcp) {
+            (Some(port), _) => port.display().to_string(),
+            (None, Some(address)) => address.to_string(),
+            (None, None) => "-".to_string(),
+        }
+    }
+
+    /// The file this device's log goes to: its name, or the last part of its port or its address,
+    /// made safe for a file name and numbered if an earlier device in the batch already took it.
+    fn log_name(&self, taken: &[String]) -> String {
+        let stem = match (&self.name, &self.port, self.tcp) {
+            (Some(name), _, _) => name.clone(),
+            (None, Some(port), _) => port.file_name().map_or_else(|| port.display().to_string(), |name| name.to_string_lossy().into_owned()),
+            (None, None, Some(address)) => address.to_string(),
+            (None, None, None) => "device".to_string(),
+        };
+        let stem: String = stem
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' { c } else { '_' })
+            .collect();
+
+        let mut log_name = format!("{}.log", stem);
+        let mut n = 2;
+        while taken.contains(&log_name) {
+            log_name = format!("{}-{}.log", stem, n);
+            n += 1;
+        }
+        log_name
+    }
+}
+
+fn batch(opt: &Opt, path: &Path, output_dir: Option<&Path>) -> Result<(), FlasherError> {
+    let text = std::fs::read_to_string(path).map_err(FlasherError::CouldntReadInput)?;
+    let manifest: Manifest = toml::from_str(&text).map_err(|e| FlasherError::InvalidManifest(e.to_string()))?;
+    let base = path.parent().unwrap_or(Path::new(""));
+    let names: Vec<String> = manifest
+        .devices
+        .iter()
+        .enumerate()
+        .map(|(i, device)| device.name.clone().unwrap_or_else(|| format!("#{}", i + 1)))
+        .collect();
 
-    let port = opt.port.expect("Only serial port is allowed right now");
-    let port =  port.to_str().unwrap_or("Invalid UTF-8 path");;
-    let port = "/dev/cu.usbserial-110";
+    if !opt.yes && std::io::stdout().is_terminal() {
+        note!("About to flash {} devices:", manifest.devices.len());
+        for (name, device) in names.iter().zip(&manifest.devices) {
+            note!("  {:<16} {:<24} {}", name, device.target(), base.join(&device.image).display());
+        }
+        if !ask("Proceed? [y/N] ") {
+            note!("Not flashing");
+            exit(1);
+        }
+    }
 
-    let mut port = serialport::new(port, 9600)
-        .timeout(Duration::from_millis(10000))
-        .open().expect("Failed to open port");
+    if let Some(dir) = output_dir {
+        std::fs::create_dir_all(dir).map_err(FlasherError::CouldntWriteOutput)?;
+    }
 
-    send_command(SerialFlasherCommand::Hello, &mut port).unwrap();
-    let resp = send_command(SerialFlasherCommand::PrepareForUpdate, &mut port).unwrap();
-    if resp == SerialFlasherResponse::Ack {
-        for command in write_commands {
-            let resp = send_command(command, &mut port).unwrap();
+    let cancel = install_cancel();
+    let mut results = Vec::new();
+    let mut log_names = Vec::new();
+    for (name, device) in names.iter().zip(&manifest.devices) {
+        if cancel.load(Ordering::Relaxed) {
+            results.push((name, device.target(), 130, "not attempted, interrupted".to_string()));
+            continue;
+        }
 
-            if resp == SerialFlasherResponse::Nack {
-                panic!("We received a NACK in response to a write. No bueno!");
+        if let Some(dir) = output_dir {
+            let log_name = device.log_name(&log_names);
+            let created = File::create(dir.join(&log_name)).map_err(FlasherError::CouldntWriteOutput);
+            log_names.push(log_name);
+            match created {
+                Ok(file) => {
+                    log::copy_to(Some(file));
+                }
+                // Without its log the device isn't flashed, but the rest of the batch goes on.
+                Err(e) => {
+                    note!("\n=== {} ({}) ===\n{}", name, device.target(), e);
+                    results.push((name, device.target(), exit_code(&e), format!("FAIL: {}", e)));
+                    continue;
+                }
             }
         }
-        let r = send_command(SerialFlasherCommand::FinishedWriting, &mut port).unwrap();
-        if r == SerialFlasherResponse::Ack {
-            send_command(SerialFlasherCommand::MarkUpdated, &mut port).unwrap();
+
+        note!("\n=== {} ({}) ===", name, device.target());
+        let res = match device.options(opt, base) {
+            Ok(mut device_opt) => {
+                // A log is for finding out afterwards what went wrong, so it should say how long each step took.
+                device_opt.timings |= output_dir.is_some();
+                flash(&device_opt, cancel.clone())
+            }
+            Err(e) => Err(FlasherError::InvalidManifest(format!("device {}: {}", name, e))),
+        };
+        let (code, result) = match res {
+            Ok(()) => (0, "pass".to_string()),
+            Err(e) => {
+                note!("{}", e);
+                (exit_code(&e), format!("FAIL: {}", e))
+            }
+        };
+        log::copy_to(None);
+        results.push((name, device.target(), code, result));
+    }
+
+    println!("{:<16} {:<24} {:>4}  RESULT", "DEVICE", "TARGET", "EXIT");
+    for (name, target, code, result) in &results {
+        println!("{:<16} {:<24} {:>4}  {}", name, target, code, result);
+    }
+
+    let failed = results.iter().filter(|(_, _, code, _)| *code != 0).count();
+    if failed > 0 {
+        note!("{} of {} devices failed", failed, results.len());
+        exit(1);
+    }
+    Ok(())
+}
+
+fn list_blocks(input: &Path, offset: Option<u32>) -> Result<(), FlasherError> {
+    let buffer = image::read_input(input)?;
+    let blocks = image::parse_blocks(&buffer)?;
+
+    println!("{:>6}  {:<10}  {:<10}  {:>4}  {:<10}  family", "block", "target", "relative", "len", "flags");
+    for b in &blocks {
+        let family = if b.flags.contains(uftwo::Flags::FamilyId) {
+            format!("0x{:08x}", b.board_family_id_or_file_size)
+        } else {
+            "-".to_string()
+        };
+        let skipped = if offset.is_some_and(|offset| offset > b.target_addr) { "  (below --offset, skipped)" } else { "" };
+        let relative = match offset {
+            Some(offset) if offset <= b.target_addr => format!("0x{:08x}", b.target_addr - offset),
+            _ => "-".to_string(),
+        };
+
+        println!(
+            "{:>6}  0x{:08x}  {:<10}  {:>4}  0x{:08x}  {}{}",
+            b.block, b.target_addr, relative, b.data_len, b.flags.bits(), family, skipped
+        );
+    }
+
+    let mut ranges: Vec<(u64, u64)> = blocks
+        .iter()
+        .map(|b| (b.target_addr as u64, b.target_addr as u64 + b.data_len as u64))
+        .collect();
+    ranges.sort();
+
+    let (Some(first), Some(last)) = (ranges.first(), ranges.iter().map(|r| r.1).max()) else {
+        println!("No blocks in {:?}", input);
+        return Ok(());
+    };
+
+    println!();
+    println!(
+        "Address range: 0x{:08x}..0x{:08x} ({} blocks, {} payload bytes)",
+        first.0,
+        last,
+        blocks.len(),
+        blocks.iter().map(|b| b.data_len as u64).sum::<u64>()
+    );
+
+    let mut end = first.1;
+    for &(start, stop) in &ranges[1..] {
+        if start > end {
+            println!("Gap: 0x{:08x}..0x{:08x} ({} bytes)", end, start, start - end);
+        } else if start < end {
+            println!("Overlap: 0x{:08x}..0x{:08x} ({} bytes)", start, end.min(stop), end.min(stop) - start);
         }
+        end = end.max(stop);
     }
+
+    Ok(())
 }
 
-fn send_command(cmd: SerialFlasherCommand, port: &mut Box<dyn SerialPort>) -> Result<SerialFlasherResponse, FlasherError> {
-    println!("Sending {:?}", cmd);
-    let ser = postcard::to_stdvec_cobs(&cmd).expect("Failed to serialize");
+fn checksum(opt: &Opt, input: &Path) -> Result<(), FlasherError> {
+    let data = read_input(opt, input)?;
 
-    println!("Serialized: {:?}", ser);
+    for check in Flasher::planned_checks(&data, &opt.flash_options())? {
+        if let Some(line) = describe_check(&check) {
+            println!("{}", line);
+        }
+    }
+    Ok(())
+}
+
+/// A compare as its relative start, length and checksum.
+fn describe_check(check: &SerialFlasherCommand) -> Option<String> {
+    match check {
+        SerialFlasherCommand::CompareChecksum(length, checksum) => Some(format!("0x0 {} {}", length, checksum)),
+        SerialFlasherCommand::CompareRegionChecksum(start, length, checksum) => {
+            Some(format!("0x{:x} {} {}", start, length, checksum))
+        }
+        _ => None,
+    }
+}
+
+fn pack(input: &Path, output: &Path, offset: u32, family_id: u32) -> Result<(), FlasherError> {
+    let data = image::read_input(input)?;
+    let uf2 = image::pack_uf2(&data, offset, family_id)?;
+
+    if !image::KNOWN_FAMILY_IDS.iter().any(|(id, _)| *id == family_id) {
+        note!("Warning: family ID 0x{:08x} isn't one we know about", family_id);
+    }
+    std::fs::write(output, &uf2).map_err(FlasherError::CouldntWriteOutput)?;
+
+    note!("Packed {} bytes into {} blocks at 0x{:08x} in {:?}", data.len(), uf2.len() / image::UF2_BLOCK_SIZE, offset, output);
+
+    Ok(())
+}
+
+fn check(opt: &Opt) -> Result<(), FlasherError> {
+    let mut flasher = open_flasher(opt)?;
+    flasher.hello()?;
+
+    let target = match (&opt.port, opt.tcp) {
+        (Some(port), _) => format!("{} at {} baud", port.display(), opt.baud_rate()),
+        (_, Some(address)) => address.to_string(),
+        (None, None) => unreachable!("open_flasher needs a port or an address"),
+    };
+    println!("The device on {} answered the handshake", target);
+    Ok(())
+}
+
+fn ping(opt: &Opt, count: u32) -> Result<(), FlasherError> {
+    let mut flasher = open_flasher(opt)?;
+    let mut round_trips = Vec::new();
+
+    for i in 1..=count {
+        match flasher.ping() {
+            Ok(rtt) => {
+                println!("Ping {}: {:.1} ms", i, rtt.as_secs_f64() * 1000.0);
+                round_trips.push(rtt);
+            }
+            Err(e) => println!("Ping {}: {}", i, e),
+        }
+    }
+
+    let lost = count as usize - round_trips.len();
+    let (Some(min), Some(max)) = (round_trips.iter().min(), round_trips.iter().max()) else {
+        return Err(FlasherError::NoResponse);
+    };
+    let avg = round_trips.iter().sum::<Duration>() / round_trips.len() as u32;
+
+    println!();
+    println!(
+        "{} sent, {} lost, round trip min/avg/max = {:.1}/{:.1}/{:.1} ms",
+        count,
+        lost,
+        min.as_secs_f64() * 1000.0,
+        avg.as_secs_f64() * 1000.0,
+        max.as_secs_f64() * 1000.0
+    );
+
+    Ok(())
+}
+
+fn probe(opt: &Opt, rates: &[u32], timeout: Duration) -> Result<(), FlasherError> {
+    let mut flasher = open_flasher(opt)?;
+    flasher.set_timeout(timeout);
+
+    for &rate in rates {
+        note!("Trying {} baud", rate);
+        flasher.set_baud_rate(rate)?;
 
-    let chunks = ser.chunks(16);
+        match flasher.hello() {
+            Ok(()) => {
+                println!("The device answered at {} baud, use --baud {}", rate, rate);
+                return Ok(());
+            }
+            Err(e @ FlasherError::Nack { .. }) => {
+                println!("The device answered at {} baud, but with: {}", rate, e);
+                return Ok(());
+            }
+            Err(FlasherError::DeviceUnreachable { .. }) => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Err(FlasherError::NoBaudRateAnswered { rates: rates.to_vec() })
+}
+
+fn print_log(opt: &Opt) -> Result<(), FlasherError> {
+    let mut flasher = open_flasher(opt)?;
+    let log = flasher.get_log()?;
+
+    if log.is_empty() {
+        note!("The device's log is empty");
+    } else {
+        println!("{}", log);
+    }
+    Ok(())
+}
+
+fn connect(opt: &Opt) -> Result<Flasher, TransportOpenError> {
+    if let Some(address) = opt.tcp {
+        #[cfg(feature = "tls")]
+        if opt.tls {
+            let tls = TlsOptions { ca_cert: opt.ca_cert.clone(), server_name: opt.server_name.clone() };
+            return Flasher::connect_tls(address, &tls);
+        }
+        return Flasher::connect(address);
+    }
+
+    let Some(port) = &opt.port else {
+        structopt::clap::Error::with_description(
+            "Either --port or --tcp needs to be provided",
+            ErrorKind::MissingRequiredArgument,
+        )
+        .exit()
+    };
+    let port = port
+        .to_str()
+        .ok_or_else(|| TransportOpenError::BadAddress(port.to_string_lossy().into_owned()))?;
+
+    Flasher::open(port, opt.baud_rate())
+}
 
-    for chunk in chunks {
-        println!("Writing chunk");
-        port.write(chunk).expect("Write failed!");
-        sleep(Duration::from_millis(1));
+fn open_flasher(opt: &Opt) -> Result<Flasher, FlasherError> {
+    let mut flasher = connect(opt)?;
+    if let Some(dump_sent) = &opt.dump_sent {
+        flasher.dump_sent_to(dump_sent).map_err(FlasherError::CouldntWriteOutput)?;
+    }
+    if let Some(chunk_size) = opt.chunk_size {
+        flasher.set_chunk_size(chunk_size);
+    }
+    flasher.set_frame_aligned(opt.frame_aligned);
+    flasher.set_hello_attempts(opt.hello_attempts);
+    flasher.set_flush_before_hello(!opt.no_flush);
+    flasher.set_accept_bare_checksum_ack(opt.accept_bare_checksum_ack);
+    flasher.set_page_retries(opt.retry_on_nack, opt.retry_on_timeout, opt.retry_on_decode);
+    flasher.set_keep_going(opt.keep_going);
+    flasher.set_recover_reset(opt.recover_reset);
+    flasher.set_max_total_retries(opt.max_total_retries);
+    if opt.timings {
+        flasher.record_timings();
+    }
+    if opt.no_page_crc {
+        note!("Warning: --no-page-crc disables the per-page integrity check, corrupted pages will only be caught by the final checksum");
+        flasher.set_page_crc(false);
     }
 
-    let mut serial_buf: Vec<u8> = vec![0; 32];
-    let res = port.read(serial_buf.as_mut_slice());
+    if let Some(fd) = opt.progress_fd {
+        let events = progress_file(fd).map_err(FlasherError::CouldntWriteOutput)?;
+        flasher.on_progress(move |p| {
+            // A parent that stopped listening shouldn't break the flash.
+            let _ = writeln!(&*events, r#"{{"phase":"{}","done":{},"total":{}}}"#, p.phase.name(), p.done, p.total);
+        });
+    }
+
+    Ok(flasher)
+}
+
+#[cfg(unix)]
+fn simulate(flash_image: Option<&Path>, fill_byte: u8, reset_after_prepare: bool) -> Result<(), FlasherError> {
+    use variegated_serial_updater::pty;
+    use variegated_serial_updater::sim::Simulator;
+
+    let initial = match flash_image {
+        Some(path) if path.exists() => std::fs::read(path).map_err(FlasherError::CouldntReadInput)?,
+        _ => Vec::new(),
+    };
+    let mut simulator = Simulator::with_flash(initial, fill_byte);
+    if reset_after_prepare {
+        simulator.reset_after_prepare();
+    }
 
-    if let Ok(len) = res {
-        let resp = from_bytes_cobs::<SerialFlasherResponse>(&mut serial_buf[..len]);
-        if let Ok(resp) = resp {
-            println!("Received response: {:?}", resp);
-            return Ok(resp);
+    let (mut master, pty) = pty::open_pair()
+        .map_err(|error| TransportOpenError::Other { port: "a new pseudo-terminal".to_string(), error })?;
+
+    println!("{}", pty.path());
+    std::io::stdout().flush().map_err(FlasherError::CouldntWriteOutput)?;
+
+    simulator.serve(&mut master).map_err(FlasherError::TransportFailed)?;
+
+    if let Some(path) = flash_image.filter(|_| simulator.is_updated()) {
+        std::fs::write(path, simulator.flash()).map_err(FlasherError::CouldntWriteOutput)?;
+        note!("Wrote {} bytes of flash to {}", simulator.flash().len(), path.display());
+    }
+
+    // Closing the master throws away anything the flasher hasn't read yet, including the last Ack.
+    std::thread::sleep(Duration::from_secs(1));
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn simulate(_flash_image: Option<&Path>, _fill_byte: u8, _reset_after_prepare: bool) -> Result<(), FlasherError> {
+    Err(FlasherError::TransportFailed(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "simulate needs pseudo-terminals, which are only supported on Unix",
+    )))
+}
+
+/// The --progress-fd descriptor as a file that is never closed. A batch opens a flasher per
+/// device, and closing the descriptor with the first one would hand its number to the next
+/// device's port or log file.
+#[cfg(unix)]
+fn progress_file(fd: i32) -> std::io::Result<ManuallyDrop<File>> {
+    use std::os::unix::io::FromRawFd;
+
+    // SAFETY: --progress-fd is a descriptor the parent process opened for us and that nothing
+    // else in this process uses. It stays the parent's to close.
+    Ok(ManuallyDrop::new(unsafe { File::from_raw_fd(fd) }))
+}
+
+#[cfg(not(unix))]
+fn progress_file(_fd: i32) -> std::io::Result<ManuallyDrop<File>> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "--progress-fd is only supported on Unix"))
+}
+
+fn run(opt: Opt) -> Result<(), FlasherError> {
+    let cancel = install_cancel();
+    flash(&opt, cancel)
+}
+
+/// Makes the first Ctrl-C cancel the flash in progress and the second one exit right away.
+fn install_cancel() -> Arc<AtomicBool> {
+    let cancel = Arc::new(AtomicBool::new(false));
+    let interrupted = cancel.clone();
+    let handler = ctrlc::set_handler(move || {
+        if interrupted.swap(true, Ordering::Relaxed) {
+            exit(130);
+        }
+        note!("Aborting the update after the current command, press Ctrl-C again to quit right away");
+    });
+    if let Err(e) = handler {
+        note!("Warning: couldn't install the Ctrl-C handler, so Ctrl-C won't abort the update: {}", e);
+    }
+    cancel
+}
+
+fn flash(opt: &Opt, cancel: Arc<AtomicBool>) -> Result<(), FlasherError> {
+    let Some(input) = &opt.input else {
+        structopt::clap::Error::with_description(
+            "The following required arguments were not provided:\n    <input>",
+            ErrorKind::MissingRequiredArgument,
+        )
+        .exit();
+    };
+
+    note!("Input file: {:?}", input);
+    let data = read_input(opt, input)?;
+    let flash_options = opt.flash_options();
+
+    // Refuse a bad image before touching the port.
+    let plan = Flasher::plan(&data, &flash_options)?;
+    let checksum = plan.checksum();
+    // With --checksum-region the device only confirms the regions, not the whole image.
+    let regions: Vec<String> = plan.checks().iter().filter_map(describe_check).collect();
+
+    if !opt.yes && std::io::stdout().is_terminal() && !confirm(opt, &plan.writes(), &checksum) {
+        note!("Not flashing");
+        exit(1);
+    }
+
+    let mut flasher = open_flasher(opt)?;
+    flasher.set_cancel(cancel);
+
+    let res = flasher.flash_plan(plan);
+
+    let stats = flasher.link_stats();
+    if stats.error_rate() * 100.0 > opt.error_rate_warn {
+        note!(
+            "\nWarning: {:.1}% of commands failed ({} NACKs, {} timeouts, {} garbled answers in {} commands). The link is marginal, try a lower --baud or a smaller --chunk-size",
+            stats.error_rate() * 100.0,
+            stats.nacks,
+            stats.timeouts,
+            stats.decode_errors,
+            stats.commands
+        );
+    }
+
+    match opt.max_total_retries {
+        Some(max) => note!("Used {} of {} retries", flasher.retries_used(), max),
+        None if flasher.retries_used() > 0 => note!("Used {} retries", flasher.retries_used()),
+        None => {}
+    }
+
+    if let Some(timings) = flasher.timings() {
+        note!("\n{}", timings);
+    }
+
+    if res.is_ok() {
+        if opt.checksum_regions.is_empty() {
+            note!("The device holds the image with {}", checksum);
         } else {
-            println!("Couldn't deserialize response: {:?}", resp.unwrap_err());
-            return Err(FlasherError::CouldntDeserialize);
+            note!("The device holds the image, with these regions confirmed:");
+            for region in &regions {
+                note!("  {}", region);
+            }
+        }
+    }
+
+    if let Err(e) = &res {
+        if opt.exit_bits {
+            note!("{}", e);
+            let phase = flasher.last_phase();
+            // exit() skips destructors, and the frame dump of a failed session is the one worth keeping.
+            drop(flasher);
+            exit(phase_exit_bits(phase));
         }
+    }
+    res
+}
+
+/// Shows what is about to be written where and asks whether to go ahead.
+fn confirm(opt: &Opt, writes: &[(RelativeAddress, usize)], checksum: &ImageChecksum) -> bool {
+    let offset = opt.required_offset() as u64;
+    let start = writes.iter().map(|&(address, _)| address as u64).min().unwrap_or(0);
+    let end = writes.iter().map(|&(address, len)| (address + len) as u64).max().unwrap_or(0);
+    let size: usize = writes.iter().map(|&(_, len)| len).sum();
+    let target = match (&opt.port, opt.tcp) {
+        (Some(port), _) => format!("{} at {} baud", port.display(), opt.baud_rate()),
+        (None, Some(address)) => address.to_string(),
+        (None, None) => "no target".to_string(),
+    };
+
+    note!("About to flash:");
+    note!("  range     0x{:08x} .. 0x{:08x}", offset + start, offset + end);
+    note!("  size      {} bytes in {} pages", size, writes.len());
+    note!("  target    {}", target);
+    note!("  checksum  {}", checksum);
+    ask("Proceed? [y/N] ")
+}
+
+/// Asks a yes/no question on the terminal. Anything but a yes, including a closed stdin, is a no.
+fn ask(question: &str) -> bool {
+    eprint!("{}", question);
+    let mut answer = String::new();
+    let _ = std::io::stdin().read_line(&mut answer);
+    matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes")
+}
+
+#[cfg(feature = "mmap")]
+fn read_input(opt: &Opt, input: &Path) -> Result<image::Input, FlasherError> {
+    if opt.mmap {
+        image::map_input(input)
     } else {
-        println!("Didn't read a response");
-        return Err(FlasherError::NoResponse);
+        image::read_input(input).map(image::Input::Read)
     }
+}
+
+#[cfg(not(feature = "mmap"))]
+fn read_input(_opt: &Opt, input: &Path) -> Result<image::Input, FlasherError> {
+    image::read_input(input).map(image::Input::Read)
+}
 
-}
\ No newline at end of file
+/// The --exit-bits exit code for a flash that failed in `phase`.
+fn phase_exit_bits(phase: Option<Phase>) -> i32 {
+    match phase {
+        Some(Phase::Handshake) => 1 << 0,
+        Some(Phase::Erase) => 1 << 1,
+        Some(Phase::Write) => 1 << 2,
+        Some(Phase::Verify) => 1 << 3,
+        Some(Phase::Mark | Phase::Complete) => 1 << 4,
+        None => 1 << 5,
+    }
+}
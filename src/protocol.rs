@@ -0,0 +1,219 @@
+use serde::{Deserialize, Serialize};
+use serde_big_array::Array;
+
+pub const PAGE_SIZE: usize = 256;
+
+pub type RelativeAddress = usize;
+pub type Length = u32;
+pub type Page = Array<u8, PAGE_SIZE>;
+pub type Crc8Checksum = u8;
+pub type Sha256Checksum = [u8; 32];
+pub type Crc32Checksum = u32;
+
+/// A relative address together with the offset it counts from. Displays as both, like
+/// `rel 0x2000 / abs 0x10012000`, so log output can be matched against a linker map.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PageAddress {
+    pub relative: RelativeAddress,
+    pub offset: u32,
+}
+
+impl PageAddress {
+    pub fn new(relative: RelativeAddress, offset: u32) -> Self {
+        PageAddress { relative, offset }
+    }
+
+    pub fn absolute(&self) -> u64 {
+        self.offset as u64 + self.relative as u64
+    }
+}
+
+impl std::fmt::Display for PageAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "rel 0x{:x} / abs 0x{:08x}", self.relative, self.absolute())
+    }
+}
+
+pub const CRC8: crc::Crc<u8> = crc::Crc::<u8>::new(&crc::CRC_8_SMBUS);
+pub const CRC32: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+
+/// Which hash a CompareChecksum carries. The device echoes it back in its ChecksumAck, so a host
+/// and device that disagree on the hash get a clear error instead of a NACK on every compare.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+#[repr(u8)]
+pub enum ChecksumAlgorithm {
+    Sha256 = 0,
+    /// Much cheaper for the device to compute, when a cryptographic hash isn't needed.
+    Crc32 = 1,
+}
+
+impl std::str::FromStr for ChecksumAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "sha256" => Ok(ChecksumAlgorithm::Sha256),
+            "crc32" => Ok(ChecksumAlgorithm::Crc32),
+            _ => Err(format!("Unknown checksum algorithm `{}`, expected sha256 or crc32", s)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+#[repr(u8)]
+pub enum ImageChecksum {
+    Sha256(Sha256Checksum) = 0,
+    Crc32(Crc32Checksum) = 1,
+}
+
+impl ImageChecksum {
+    pub fn algorithm(&self) -> ChecksumAlgorithm {
+        match self {
+            ImageChecksum::Sha256(_) => ChecksumAlgorithm::Sha256,
+            ImageChecksum::Crc32(_) => ChecksumAlgorithm::Crc32,
+        }
+    }
+}
+
+impl std::fmt::Display for ImageChecksum {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImageChecksum::Sha256(hash) => {
+                write!(f, "SHA-256 ")?;
+                hash.iter().try_for_each(|b| write!(f, "{:02x}", b))
+            }
+            ImageChecksum::Crc32(crc) => write!(f, "CRC32 0x{:08x}", crc),
+        }
+    }
+}
+
+/// What goes into WritePage's CRC8 when the session was opened with `page_crc: false`.
+pub const NO_PAGE_CRC: Crc8Checksum = 0;
+
+/// Per-session settings the host asks for in HelloWithOptions. A plain Hello means the defaults.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub struct SessionOptions {
+    /// Whether the device checks each page against its CRC8. When it doesn't, the host sends
+    /// [`NO_PAGE_CRC`] instead and corruption is only caught by the final CompareChecksum.
+    pub page_crc: bool,
+}
+
+impl Default for SessionOptions {
+    fn default() -> Self {
+        SessionOptions { page_crc: true }
+    }
+}
+
+/// A command from the host to the device.
+///
+/// Postcard puts the variant's position on the wire, so the discriminants spell out what deployed
+/// bootloaders expect and must always match the position: new commands are appended with the
+/// next number, never inserted, and none are removed or reordered. `tests/wire_format.rs` holds
+/// the bytes of every variant to catch a slip.
+#[derive(Serialize, Deserialize, Debug)]
+#[allow(clippy::large_enum_variant)]
+#[repr(u8)]
+pub enum SerialFlasherCommand {
+    Hello = 0,
+    PrepareForUpdate = 1,
+    /// Programs one page. Writes are idempotent within a session: the host resends a page with
+    /// exactly the same address, data and CRC8 when it didn't get an answer, and the device may
+    /// already have written it. A device that gets a WritePage for a page it has written since the
+    /// last PrepareForUpdate must ACK it without programming again if the data is identical (the
+    /// stored CRC8 makes for a cheap first check, the bytes decide), and NACK it if it differs.
+    WritePage(RelativeAddress, Page, Crc8Checksum) = 2,
+    FinishedWriting = 3,
+    CompareChecksum(Length, ImageChecksum) = 4,
+    MarkUpdated = 5,
+    /// Does nothing but get an Ack back, for measuring the round trip.
+    Ping = 6,
+    /// A Hello that also asks for non-default session options. Devices that don't support them
+    /// NACK it.
+    HelloWithOptions(SessionOptions) = 7,
+    /// Discards a partial update, which then must not be booted. Sent when a flash fails after
+    /// PrepareForUpdate.
+    Abort = 8,
+    /// Asks for the contents of the bootloader's diagnostic log, answered with a Log.
+    GetLog = 9,
+    /// Asks for the current contents of a page, answered with PageData.
+    ReadPage(RelativeAddress) = 10,
+    /// Like CompareChecksum, over `Length` bytes from the address instead of from the start of
+    /// the image. Answered the same way.
+    CompareRegionChecksum(RelativeAddress, Length, ImageChecksum) = 11,
+    /// Re-enters the update after the device reset during it, without erasing again. Pages
+    /// written before the reset stay as they are.
+    PrepareForUpdateNoErase = 12,
+}
+
+impl SerialFlasherCommand {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SerialFlasherCommand::Hello => "Hello",
+            SerialFlasherCommand::PrepareForUpdate => "PrepareForUpdate",
+            SerialFlasherCommand::WritePage(..) => "WritePage",
+            SerialFlasherCommand::FinishedWriting => "FinishedWriting",
+            SerialFlasherCommand::CompareChecksum(..) => "CompareChecksum",
+            SerialFlasherCommand::MarkUpdated => "MarkUpdated",
+            SerialFlasherCommand::Ping => "Ping",
+            SerialFlasherCommand::HelloWithOptions(..) => "HelloWithOptions",
+            SerialFlasherCommand::Abort => "Abort",
+            SerialFlasherCommand::GetLog => "GetLog",
+            SerialFlasherCommand::ReadPage(..) => "ReadPage",
+            SerialFlasherCommand::CompareRegionChecksum(..) => "CompareRegionChecksum",
+            SerialFlasherCommand::PrepareForUpdateNoErase => "PrepareForUpdateNoErase",
+        }
+    }
+
+    /// Serializes each field on its own to find out which one postcard chokes on.
+    pub fn failing_field(&self) -> Option<&'static str> {
+        fn fails<T: Serialize>(value: &T) -> bool {
+            postcard::to_stdvec(value).is_err()
+        }
+
+        match self {
+            SerialFlasherCommand::WritePage(address, page, checksum) => {
+                if fails(address) {
+                    Some("address")
+                } else if fails(page) {
+                    Some("page")
+                } else if fails(checksum) {
+                    Some("checksum")
+                } else {
+                    None
+                }
+            }
+            SerialFlasherCommand::CompareChecksum(length, checksum)
+            | SerialFlasherCommand::CompareRegionChecksum(_, length, checksum) => {
+                if fails(length) {
+                    Some("length")
+                } else if fails(checksum) {
+                    Some("checksum")
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A device's answer to a command. Numbered and only ever appended to like
+/// [`SerialFlasherCommand`], with 5 taken by `Unknown`.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[repr(u8)]
+pub enum SerialFlasherResponse {
+    Ack = 0,
+    Nack = 1,
+    /// The image checksum matched, computed with the given algorithm.
+    ChecksumAck(ChecksumAlgorithm) = 2,
+    /// The bootloader's diagnostic log, oldest entry first. Whatever the device keeps there, like
+    /// the reset reason, the last error or a flash fault address.
+    Log(String) = 3,
+    /// The contents of the page asked for with ReadPage.
+    PageData(Box<Page>) = 4,
+    /// Any response added to the protocol after this host was built. Never sent, the flasher
+    /// turns it into [`FlasherError::UnknownResponse`](crate::FlasherError::UnknownResponse).
+    /// New responses are numbered from 6.
+    #[serde(other)]
+    Unknown = 5,
+}
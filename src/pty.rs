@@ -0,0 +1,27 @@
+use serialport::{SerialPort, TTYPort};
+
+/// The slave end of a pseudo-terminal pair. It's held open so reads on the master don't fail
+/// with EIO before the flasher has opened the slave by path, or between two sessions.
+pub struct Pty {
+    _slave: TTYPort,
+    path: String,
+}
+
+impl Pty {
+    /// Device path of the slave end, to open like any other serial port.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+/// Opens a pseudo-terminal pair. The master is for the simulator (or a test) to drive; the
+/// flasher opens the slave through [`Pty::path`], exercising the same serial path as real
+/// hardware.
+pub fn open_pair() -> serialport::Result<(TTYPort, Pty)> {
+    let (master, slave) = TTYPort::pair()?;
+    let path = slave
+        .name()
+        .ok_or_else(|| serialport::Error::new(serialport::ErrorKind::Unknown, "pty slave has no path"))?;
+
+    Ok((master, Pty { _slave: slave, path }))
+}
@@ -0,0 +1,223 @@
+use std::collections::{BTreeSet, VecDeque};
+use std::io::{self, Read, Write};
+use postcard::from_bytes_cobs;
+use serde_big_array::Array;
+use crate::image::{self, ERASED_BYTE};
+use crate::protocol::{RelativeAddress, SerialFlasherCommand, SerialFlasherResponse, SessionOptions, CRC8, PAGE_SIZE};
+
+/// A device that keeps its flash in memory and answers the update protocol the way the bootloader
+/// does, so the flasher can be run end to end without hardware.
+#[derive(Debug)]
+pub struct Simulator {
+    flash: Vec<u8>,
+    /// What erased flash reads back as.
+    fill: u8,
+    /// Pages programmed since the last PrepareForUpdate, which can't be programmed again.
+    written: BTreeSet<RelativeAddress>,
+    updated: bool,
+    /// Whether an update is in progress, so WritePage is allowed.
+    preparing: bool,
+    /// Drop out of the update right after the next erase, like a board that browns out.
+    reset_after_prepare: bool,
+    options: SessionOptions,
+    /// The diagnostic log returned by GetLog, keeping the last [`LOG_ENTRIES`] entries.
+    log: VecDeque<String>,
+}
+
+const LOG_ENTRIES: usize = 32;
+
+impl Default for Simulator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Simulator {
+    pub fn new() -> Self {
+        Self::with_flash(Vec::new(), ERASED_BYTE)
+    }
+
+    /// A device whose flash starts out as `flash`, and reads back as `fill` where it's erased.
+    pub fn with_flash(flash: Vec<u8>, fill: u8) -> Self {
+        Simulator {
+            flash,
+            fill,
+            written: BTreeSet::new(),
+            updated: false,
+            preparing: false,
+            reset_after_prepare: false,
+            options: SessionOptions::default(),
+            log: VecDeque::new(),
+        }
+    }
+
+    /// Flash contents relative to the update offset, up to the end of the highest written page or
+    /// of the initial flash, whichever is further.
+    pub fn flash(&self) -> &[u8] {
+        &self.flash
+    }
+
+    /// Whether MarkUpdated has been received since the last PrepareForUpdate.
+    pub fn is_updated(&self) -> bool {
+        self.updated
+    }
+
+    /// Makes the next PrepareForUpdate reset the device after erasing, so it answers the Ack but
+    /// NACKs pages until the host handshakes again and resumes with PrepareForUpdateNoErase.
+    pub fn reset_after_prepare(&mut self) {
+        self.reset_after_prepare = true;
+    }
+
+    fn note(&mut self, entry: String) {
+        if self.log.len() == LOG_ENTRIES {
+            self.log.pop_front();
+        }
+        self.log.push_back(entry);
+    }
+
+    pub fn respond(&mut self, command: &SerialFlasherCommand) -> SerialFlasherResponse {
+        match command {
+            SerialFlasherCommand::Hello => {
+                self.options = SessionOptions::default();
+                SerialFlasherResponse::Ack
+            }
+            SerialFlasherCommand::HelloWithOptions(options) => {
+                self.options = *options;
+                SerialFlasherResponse::Ack
+            }
+            SerialFlasherCommand::Ping | SerialFlasherCommand::FinishedWriting => {
+                SerialFlasherResponse::Ack
+            }
+            SerialFlasherCommand::PrepareForUpdate => {
+                self.flash.fill(self.fill);
+                self.written.clear();
+                self.updated = false;
+                self.note("Erased the update slot".to_string());
+                self.preparing = true;
+                if std::mem::take(&mut self.reset_after_prepare) {
+                    self.preparing = false;
+                    self.options = SessionOptions::default();
+                    self.note("Reset after the erase".to_string());
+                }
+                SerialFlasherResponse::Ack
+            }
+            SerialFlasherCommand::PrepareForUpdateNoErase => {
+                self.preparing = true;
+                self.note("Resumed the update without erasing".to_string());
+                SerialFlasherResponse::Ack
+            }
+            SerialFlasherCommand::WritePage(address, page, checksum) => {
+                if !self.preparing {
+                    self.note(format!("NACKed page at 0x{:x}: no update in progress", address));
+                    return SerialFlasherResponse::Nack;
+                }
+                if self.options.page_crc && CRC8.checksum(&page[..]) != *checksum {
+                    self.note(format!("NACKed page at 0x{:x}: CRC8 mismatch", address));
+                    return SerialFlasherResponse::Nack;
+                }
+
+                if self.written.contains(address) {
+                    // A resend after a lost Ack is a no-op, anything else would need another erase.
+                    if self.flash[*address..address + PAGE_SIZE] == page[..] {
+                        return SerialFlasherResponse::Ack;
+                    }
+                    self.note(format!("NACKed page at 0x{:x}: already written with other data", address));
+                    return SerialFlasherResponse::Nack;
+                }
+
+                if self.flash.len() < address + PAGE_SIZE {
+                    self.flash.resize(address + PAGE_SIZE, self.fill);
+                }
+                self.flash[*address..address + PAGE_SIZE].copy_from_slice(&page[..]);
+                self.written.insert(*address);
+                SerialFlasherResponse::Ack
+            }
+            SerialFlasherCommand::CompareChecksum(length, checksum) => {
+                let mut flashed = self.flash.clone();
+                flashed.resize(*length as usize, self.fill);
+
+                if image::image_checksum(&flashed, checksum.algorithm()) == *checksum {
+                    SerialFlasherResponse::ChecksumAck(checksum.algorithm())
+                } else {
+                    self.note(format!("Checksum over {} bytes doesn't match", length));
+                    SerialFlasherResponse::Nack
+                }
+            }
+            SerialFlasherCommand::CompareRegionChecksum(start, length, checksum) => {
+                let end = start + *length as usize;
+                let mut flashed = self.flash.clone();
+                flashed.resize(flashed.len().max(end), self.fill);
+
+                if image::image_checksum(&flashed[*start..end], checksum.algorithm()) == *checksum {
+                    SerialFlasherResponse::ChecksumAck(checksum.algorithm())
+                } else {
+                    self.note(format!("Checksum over 0x{:x}..0x{:x} doesn't match", start, end));
+                    SerialFlasherResponse::Nack
+                }
+            }
+            SerialFlasherCommand::Abort => {
+                self.flash.fill(self.fill);
+                self.written.clear();
+                self.updated = false;
+                // Pages after an Abort NACK until the next PrepareForUpdate, like on a real device.
+                self.preparing = false;
+                self.note("Update aborted by the host".to_string());
+                SerialFlasherResponse::Ack
+            }
+            SerialFlasherCommand::MarkUpdated => {
+                self.updated = true;
+                self.note("Marked updated".to_string());
+                SerialFlasherResponse::Ack
+            }
+            SerialFlasherCommand::ReadPage(address) => {
+                let mut page = Array([self.fill; PAGE_SIZE]);
+                let stored = self.flash.get(*address..).unwrap_or(&[]);
+                let n = stored.len().min(PAGE_SIZE);
+                page[..n].copy_from_slice(&stored[..n]);
+                SerialFlasherResponse::PageData(Box::new(page))
+            }
+            SerialFlasherCommand::GetLog => SerialFlasherResponse::Log(Vec::from(self.log.clone()).join("\n")),
+        }
+    }
+
+    /// Answers commands arriving on `port` until the device is marked updated or the port is
+    /// closed. Read timeouts are waited out; frames that don't decode are NACKed.
+    pub fn serve<P: Read + Write + ?Sized>(&mut self, port: &mut P) -> io::Result<()> {
+        let mut received = Vec::new();
+        let mut buf = [0u8; 1024];
+
+        while !self.updated {
+            let n = match port.read(&mut buf) {
+                Ok(0) => return Ok(()),
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+                Err(e) => return Err(e),
+            };
+            received.extend_from_slice(&buf[..n]);
+
+            while let Some(end) = received.iter().position(|&b| b == 0x00) {
+                let mut frame: Vec<u8> = received.drain(..=end).collect();
+                if frame.len() == 1 {
+                    continue;
+                }
+
+                let response = match from_bytes_cobs::<SerialFlasherCommand>(&mut frame) {
+                    Ok(command) => {
+                        let response = self.respond(&command);
+                        eprintln!("Simulator: {} -> {:?}", command.name(), response);
+                        response
+                    }
+                    Err(e) => {
+                        eprintln!("Simulator: couldn't decode a frame: {}", e);
+                        SerialFlasherResponse::Nack
+                    }
+                };
+
+                let ser = postcard::to_stdvec_cobs(&response).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                port.write_all(&ser)?;
+            }
+        }
+
+        Ok(())
+    }
+}
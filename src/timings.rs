@@ -0,0 +1,81 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::Duration;
+
+/// Round trip times of the commands sent during a flash, for spotting slow devices.
+#[derive(Debug, Default)]
+pub struct Timings {
+    round_trips: BTreeMap<&'static str, Vec<Duration>>,
+    erase: Option<Duration>,
+}
+
+impl Timings {
+    /// Records how long `command` took from sending it to reading its answer. Commands that got
+    /// no answer aren't recorded.
+    pub fn record(&mut self, command: &'static str, round_trip: Duration) {
+        self.round_trips.entry(command).or_default().push(round_trip);
+    }
+
+    pub fn record_erase(&mut self, erase: Duration) {
+        self.erase = Some(erase);
+    }
+
+    pub fn round_trips(&self, command: &str) -> &[Duration] {
+        self.round_trips.get(command).map_or(&[], |r| r.as_slice())
+    }
+
+    /// Time from sending PrepareForUpdate until the device was done erasing, keepalives included.
+    pub fn erase(&self) -> Option<Duration> {
+        self.erase
+    }
+}
+
+fn ms(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000.0
+}
+
+impl fmt::Display for Timings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{:<18} {:>6} {:>9} {:>9} {:>9} {:>9}", "Command", "Count", "Min ms", "Avg ms", "P95 ms", "Max ms")?;
+
+        for (command, round_trips) in &self.round_trips {
+            let mut sorted = round_trips.clone();
+            sorted.sort();
+            let avg = sorted.iter().sum::<Duration>() / sorted.len() as u32;
+            let p95 = sorted[(sorted.len() * 95).div_ceil(100) - 1];
+
+            writeln!(
+                f,
+                "{:<18} {:>6} {:>9.1} {:>9.1} {:>9.1} {:>9.1}",
+                command,
+                sorted.len(),
+                ms(sorted[0]),
+                ms(avg),
+                ms(p95),
+                ms(sorted[sorted.len() - 1])
+            )?;
+        }
+
+        if let Some(erase) = self.erase {
+            writeln!(f, "\nErase took {:.1} ms", ms(erase))?;
+        }
+
+        let writes = self.round_trips("WritePage");
+        if !writes.is_empty() {
+            // Power of two buckets, so a few slow outliers stand out next to the bulk of the writes.
+            let mut buckets: BTreeMap<u32, usize> = BTreeMap::new();
+            for w in writes {
+                let bucket = (w.as_micros() as u64 / 1000).max(1).ilog2();
+                *buckets.entry(bucket).or_default() += 1;
+            }
+
+            writeln!(f, "\nWritePage Ack latency")?;
+            for (bucket, count) in buckets {
+                let low = if bucket == 0 { 0 } else { 1u64 << bucket };
+                writeln!(f, "{:>6}..{:<6} ms {:>6} {}", low, 1u64 << (bucket + 1), count, "#".repeat(count.min(60)))?;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,144 @@
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use serialport::{ClearBuffer, SerialPort};
+
+/// The byte stream to the bootloader. The flasher only reads with a timeout, writes, and throws
+/// away input it doesn't want, so anything that can do that can carry an update.
+pub trait Transport: Read + Write {
+    /// How long the next read may block before failing with `TimedOut` or `WouldBlock`.
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()>;
+
+    /// Drops everything received so far that hasn't been read yet.
+    fn clear_input(&mut self) -> io::Result<()>;
+
+    /// Only serial ports have a baud rate, everything else refuses.
+    fn set_baud_rate(&mut self, _baud_rate: u32) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "this transport has no baud rate"))
+    }
+}
+
+impl Transport for Box<dyn SerialPort> {
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        SerialPort::set_timeout(self.as_mut(), timeout).map_err(io::Error::from)
+    }
+
+    fn clear_input(&mut self) -> io::Result<()> {
+        self.clear(ClearBuffer::Input).map_err(io::Error::from)
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> io::Result<()> {
+        SerialPort::set_baud_rate(self.as_mut(), baud_rate).map_err(io::Error::from)
+    }
+}
+
+impl Transport for TcpStream {
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.set_read_timeout(Some(timeout))
+    }
+
+    fn clear_input(&mut self) -> io::Result<()> {
+        let stream = self.try_clone()?;
+        drain(self, &stream)
+    }
+}
+
+/// Reads `reader` until nothing more is waiting on `socket`, leaving the socket blocking again.
+fn drain(reader: &mut impl Read, socket: &TcpStream) -> io::Result<()> {
+    socket.set_nonblocking(true)?;
+    let mut buf = [0u8; 1024];
+    let result = loop {
+        match reader.read(&mut buf) {
+            Ok(0) => break Ok(()),
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => break Err(e),
+        }
+    };
+    socket.set_nonblocking(false)?;
+    result
+}
+
+#[cfg(feature = "tls")]
+pub use self::tls::{TlsOptions, TlsStream};
+
+#[cfg(feature = "tls")]
+mod tls {
+    use std::fs::File;
+    use std::io::{self, BufReader};
+    use std::net::{SocketAddr, TcpStream};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use rustls::pki_types::pem::PemObject;
+    use rustls::pki_types::{CertificateDer, ServerName};
+    use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+
+    use super::{drain, Transport};
+
+    /// A TCP connection wrapped in a TLS client session.
+    pub type TlsStream = StreamOwned<ClientConnection, TcpStream>;
+
+    /// How to check the server a TLS connection goes to.
+    #[derive(Debug, Clone, Default)]
+    pub struct TlsOptions {
+        /// PEM file with the certificates to trust. The bundled web roots when unset.
+        pub ca_cert: Option<PathBuf>,
+        /// Name the server's certificate must be for. The IP address connected to when unset.
+        pub server_name: Option<String>,
+    }
+
+    impl TlsOptions {
+        /// Connects to `address` and completes the TLS handshake, so certificate problems show up
+        /// here rather than as a missing response to the first command.
+        pub(crate) fn connect(&self, address: SocketAddr, timeout: Duration) -> Result<TlsStream, String> {
+            let mut roots = RootCertStore::empty();
+            match &self.ca_cert {
+                Some(path) => {
+                    let file = File::open(path).map_err(|e| format!("Couldn't read {}: {}", path.display(), e))?;
+                    for cert in CertificateDer::pem_reader_iter(&mut BufReader::new(file)) {
+                        let cert = cert.map_err(|e| format!("Couldn't parse {}: {}", path.display(), e))?;
+                        roots.add(cert).map_err(|e| format!("Unusable certificate in {}: {}", path.display(), e))?;
+                    }
+                    if roots.is_empty() {
+                        return Err(format!("{} holds no certificates", path.display()));
+                    }
+                }
+                None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+            }
+
+            let server_name = match &self.server_name {
+                Some(name) => ServerName::try_from(name.clone()).map_err(|e| format!("Bad server name {}: {}", name, e))?,
+                None => ServerName::from(address.ip()),
+            };
+
+            let config = ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+            let conn = ClientConnection::new(Arc::new(config), server_name).map_err(|e| e.to_string())?;
+
+            let sock = TcpStream::connect_timeout(&address, timeout).map_err(|e| e.to_string())?;
+            sock.set_read_timeout(Some(timeout)).map_err(|e| e.to_string())?;
+            let mut stream = StreamOwned::new(conn, sock);
+            while stream.conn.is_handshaking() {
+                stream
+                    .conn
+                    .complete_io(&mut stream.sock)
+                    .map_err(|e| format!("TLS handshake failed: {}", e))?;
+            }
+            Ok(stream)
+        }
+    }
+
+    impl Transport for TlsStream {
+        fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+            self.sock.set_read_timeout(Some(timeout))
+        }
+
+        fn clear_input(&mut self) -> io::Result<()> {
+            let sock = self.sock.try_clone()?;
+            drain(self, &sock)
+        }
+    }
+}
@@ -0,0 +1,31 @@
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use variegated_serial_updater::sim::Simulator;
+use variegated_serial_updater::{image, FlashOptions, Flasher, PAGE_SIZE};
+
+#[test]
+fn flashes_over_a_connection_opened_by_the_caller() {
+    let offset = 0x10004000;
+    let data: Vec<u8> = (0..2 * PAGE_SIZE).map(|i| (i * 3) as u8).collect();
+    let uf2 = image::pack_uf2(&data, offset, 0xe48bff56).unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let device = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut simulator = Simulator::new();
+        simulator.serve(&mut stream).unwrap();
+        simulator
+    });
+
+    let stream = TcpStream::connect(address).unwrap();
+    let mut flasher = Flasher::from_transport(stream.try_clone().unwrap());
+    flasher.flash_bytes(&uf2, FlashOptions::new(offset)).unwrap();
+    drop(flasher);
+
+    // The connection is still the caller's once the flasher is gone.
+    assert_eq!(stream.peer_addr().unwrap(), address);
+    let simulator = device.join().unwrap();
+    assert!(simulator.is_updated());
+    assert_eq!(&simulator.flash()[..data.len()], &data[..]);
+}
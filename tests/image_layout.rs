@@ -0,0 +1,118 @@
+//! The bytes a flash leaves in the update slot: the footer page bootloaders check at boot, and the
+//! checksums worked out over pages without ever building the slot in memory.
+
+use serde_big_array::Array;
+use variegated_serial_updater::image::{self, Endian, Pages};
+use variegated_serial_updater::protocol::{ChecksumAlgorithm, PAGE_SIZE};
+use variegated_serial_updater::RelativeAddress;
+
+const OFFSET: u32 = 0x1000_0000;
+
+/// The update slot as it reads once `pages` are written, built the slow way: every page copied
+/// over an erased slot in address order, so the higher of two overlapping pages wins and the last
+/// sent of two at the same address.
+fn materialised(pages: &Pages, fill: u8) -> Vec<u8> {
+    let mut order: Vec<usize> = (0..pages.len()).collect();
+    order.sort_by_key(|&i| pages.get(i).0);
+
+    let mut slot = vec![fill; pages.written_len()];
+    for i in order {
+        let (address, page) = pages.get(i);
+        slot[address..address + PAGE_SIZE].copy_from_slice(&page[..]);
+    }
+    slot
+}
+
+fn streamed(pages: &Pages, range: std::ops::Range<usize>) -> Vec<u8> {
+    let mut out = Vec::new();
+    image::for_each_flashed(pages, range, |bytes| out.extend_from_slice(bytes));
+    out
+}
+
+/// Pages with gaps between them, one overlapping the end of another and two at the same address.
+fn scattered(input: &[u8], fill: u8) -> Pages<'_> {
+    let mut pages = Pages::bin(input, PAGE_SIZE, fill);
+    pages.push(0x800, Array([0x11; PAGE_SIZE]));
+    pages.push(0x880, Array([0x22; PAGE_SIZE]));
+    pages.push(0xc00, Array([0x33; PAGE_SIZE]));
+    pages.push(0xc00, Array([0x44; PAGE_SIZE]));
+    pages
+}
+
+#[test]
+fn footer_keeps_its_bytes() {
+    let data: Vec<u8> = (0..300u32).map(|i| i as u8).collect();
+    let pages = Pages::bin(&data, PAGE_SIZE, 0xff);
+    let footer = image::footer_page(&pages, 0x200, OFFSET, ChecksumAlgorithm::Crc32, Endian::Big).unwrap();
+
+    // The short last page of a binary is zero-padded.
+    let mut flashed = data.clone();
+    flashed.resize(2 * PAGE_SIZE, 0);
+    let crc = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC).checksum(&flashed);
+
+    assert_eq!(&footer[0..4], b"VFTR");
+    assert_eq!(&footer[4..8], &[0x00, 0x00, 0x02, 0x00]);
+    assert_eq!(&footer[8..12], &[0, 0, 0, 1]);
+    assert_eq!(&footer[12..16], &crc.to_be_bytes());
+    assert!(footer[16..].iter().all(|&b| b == 0), "the rest of the footer isn't zero");
+
+    let little = image::footer_page(&pages, 0x200, OFFSET, ChecksumAlgorithm::Crc32, Endian::Little).unwrap();
+    assert_eq!(&little[0..4], &image::FOOTER_MAGIC.to_le_bytes());
+    assert_eq!(&little[12..16], &crc.to_le_bytes());
+}
+
+#[test]
+fn streamed_slot_matches_materialised_slot() {
+    let data: Vec<u8> = (0..700u32).map(|i| (i * 7) as u8).collect();
+
+    for fill in [0xff, 0x00] {
+        let pages = scattered(&data, fill);
+        let slot = materialised(&pages, fill);
+        assert_eq!(slot.len(), 0xd00);
+
+        let ranges = [
+            0..slot.len(),
+            0..0,
+            0x10..0x20,
+            0x2bc..0x810,
+            0x850..0x900,
+            0x900..0xc00,
+            0xbff..0xc01,
+            0xcff..0xd00,
+        ];
+        for range in ranges {
+            assert_eq!(streamed(&pages, range.clone()), slot[range.clone()], "bytes of {:x?} differ", range);
+            for algorithm in [ChecksumAlgorithm::Sha256, ChecksumAlgorithm::Crc32] {
+                assert_eq!(
+                    image::flashed_checksum(&pages, range.clone(), algorithm),
+                    image::image_checksum(&slot[range.clone()], algorithm),
+                    "{:?} of {:x?} differs",
+                    algorithm,
+                    range
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn uf2_pages_checksum_like_their_payload() {
+    let data: Vec<u8> = (0..1000u32).map(|i| (i * 13) as u8).collect();
+    let uf2 = image::pack_uf2(&data, OFFSET + 0x400, 0xe48b_ff56).unwrap();
+    let pages = Pages::uf2(&uf2, OFFSET, 0xff).unwrap();
+
+    let mut slot = vec![0xff; 0x400];
+    slot.extend_from_slice(&data);
+    slot.resize(0x400 + data.len().div_ceil(PAGE_SIZE) * PAGE_SIZE, 0);
+
+    let addresses: Vec<RelativeAddress> = pages.addresses().collect();
+    assert_eq!(addresses, [0x400, 0x500, 0x600, 0x700]);
+    assert_eq!(
+        image::flashed_checksum(&pages, 0..slot.len(), ChecksumAlgorithm::Sha256),
+        image::image_checksum(&slot, ChecksumAlgorithm::Sha256)
+    );
+    assert_eq!(
+        image::flashed_checksum(&pages, 0x3f0..0x410, ChecksumAlgorithm::Crc32),
+        image::image_checksum(&slot[0x3f0..0x410], ChecksumAlgorithm::Crc32)
+    );
+}
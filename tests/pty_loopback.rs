@@ -0,0 +1,27 @@
+#![cfg(unix)]
+
+use std::thread;
+use variegated_serial_updater::sim::Simulator;
+use variegated_serial_updater::{image, pty, FlashOptions, Flasher, PAGE_SIZE};
+
+#[test]
+fn flashes_the_simulator_over_a_pty() {
+    let offset = 0x10004000;
+    let data: Vec<u8> = (0..3 * PAGE_SIZE + 17).map(|i| (i * 7) as u8).collect();
+    let uf2 = image::pack_uf2(&data, offset, 0xe48bff56).unwrap();
+
+    let (mut master, pty) = pty::open_pair().unwrap();
+    let device = thread::spawn(move || {
+        let mut simulator = Simulator::new();
+        simulator.serve(&mut master).unwrap();
+        // Hand the master back, closing it here would discard the Ack to MarkUpdated.
+        (simulator, master)
+    });
+
+    let mut flasher = Flasher::open(pty.path(), 9600).unwrap();
+    flasher.flash_bytes(&uf2, FlashOptions::new(offset)).unwrap();
+
+    let (simulator, _master) = device.join().unwrap();
+    assert!(simulator.is_updated());
+    assert_eq!(&simulator.flash()[..data.len()], &data[..]);
+}
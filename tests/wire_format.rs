@@ -0,0 +1,69 @@
+//! The bytes deployed bootloaders expect for every command and response. A failure here means the
+//! wire format changed: append new variants instead of inserting them.
+
+use serde::Serialize;
+use serde_big_array::Array;
+use variegated_serial_updater::protocol::{
+    ChecksumAlgorithm, ImageChecksum, SerialFlasherCommand, SerialFlasherResponse, SessionOptions, PAGE_SIZE,
+};
+
+fn bytes<T: Serialize>(value: &T) -> Vec<u8> {
+    postcard::to_stdvec(value).unwrap()
+}
+
+fn concat(parts: &[&[u8]]) -> Vec<u8> {
+    parts.concat()
+}
+
+#[test]
+fn commands_keep_their_bytes() {
+    use SerialFlasherCommand::*;
+
+    let page = Array([0xab; PAGE_SIZE]);
+    let cases: Vec<(SerialFlasherCommand, Vec<u8>)> = vec![
+        (Hello, vec![0]),
+        (PrepareForUpdate, vec![1]),
+        (WritePage(0x100, page, 0x5a), concat(&[&[2, 0x80, 0x02], &[0xab; PAGE_SIZE], &[0x5a]])),
+        (FinishedWriting, vec![3]),
+        (CompareChecksum(1024, ImageChecksum::Sha256([0x11; 32])), concat(&[&[4, 0x80, 0x08, 0], &[0x11; 32]])),
+        (CompareChecksum(1024, ImageChecksum::Crc32(0xdeadbeef)), vec![4, 0x80, 0x08, 1, 0xef, 0xfd, 0xb6, 0xf5, 0x0d]),
+        (MarkUpdated, vec![5]),
+        (Ping, vec![6]),
+        (HelloWithOptions(SessionOptions { page_crc: false }), vec![7, 0]),
+        (Abort, vec![8]),
+        (GetLog, vec![9]),
+        (ReadPage(0x300), vec![10, 0x80, 0x06]),
+        (CompareRegionChecksum(0x100, 0x40, ImageChecksum::Crc32(1)), vec![11, 0x80, 0x02, 0x40, 1, 1]),
+        (PrepareForUpdateNoErase, vec![12]),
+    ];
+
+    for (command, expected) in &cases {
+        assert_eq!(&bytes(command), expected, "{} changed on the wire", command.name());
+    }
+}
+
+#[test]
+fn responses_keep_their_bytes() {
+    use SerialFlasherResponse::*;
+
+    let cases: Vec<(SerialFlasherResponse, Vec<u8>)> = vec![
+        (Ack, vec![0]),
+        (Nack, vec![1]),
+        (ChecksumAck(ChecksumAlgorithm::Sha256), vec![2, 0]),
+        (ChecksumAck(ChecksumAlgorithm::Crc32), vec![2, 1]),
+        (Log("hi".to_string()), vec![3, 2, b'h', b'i']),
+        (PageData(Box::new(Array([0x00; PAGE_SIZE]))), concat(&[&[4], &[0x00; PAGE_SIZE]])),
+    ];
+
+    for (response, expected) in &cases {
+        assert_eq!(&bytes(response), expected, "{:?} changed on the wire", response);
+        assert_eq!(&postcard::from_bytes::<SerialFlasherResponse>(expected).unwrap(), response);
+    }
+}
+
+#[test]
+fn responses_from_newer_devices_decode_as_unknown() {
+    for variant in [6u8, 9, 0x7f] {
+        assert_eq!(postcard::from_bytes::<SerialFlasherResponse>(&[variant]).unwrap(), SerialFlasherResponse::Unknown);
+    }
+}